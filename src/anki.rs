@@ -1,6 +1,13 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[cfg(test)]
+pub mod fake;
+pub mod http;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Request {
     pub action: String,
@@ -8,31 +15,445 @@ pub struct Request {
     pub params: Params,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Params {
-    pub note: Note,
+impl Request {
+    fn new(action: &str, params: Params) -> Self {
+        Request {
+            action: action.to_string(),
+            version: 6,
+            params,
+        }
+    }
+
+    pub fn add_note(note: Note) -> Self {
+        Request::new("addNote", Params::Note { note })
+    }
+
+    pub fn update_note(note: Note) -> Self {
+        Request::new("updateNote", Params::Note { note })
+    }
+
+    pub fn notes_info(notes: Vec<u64>) -> Self {
+        Request::new("notesInfo", Params::Notes { notes })
+    }
+
+    /// Adds `tags` to every note in `notes`.
+    pub fn add_tags(notes: Vec<u64>, tags: &[String]) -> Self {
+        Request::new(
+            "addTags",
+            Params::Tags {
+                notes,
+                tags: tags.join(" "),
+            },
+        )
+    }
+
+    /// Removes `tags` from every note in `notes`.
+    pub fn remove_tags(notes: Vec<u64>, tags: &[String]) -> Self {
+        Request::new(
+            "removeTags",
+            Params::Tags {
+                notes,
+                tags: tags.join(" "),
+            },
+        )
+    }
+
+    pub fn cards_info(cards: Vec<u64>) -> Self {
+        Request::new("cardsInfo", Params::Cards { cards })
+    }
+
+    pub fn suspend(cards: Vec<u64>) -> Self {
+        Request::new("suspend", Params::Cards { cards })
+    }
+
+    pub fn delete_notes(notes: Vec<u64>) -> Self {
+        Request::new("deleteNotes", Params::Notes { notes })
+    }
+
+    pub fn change_deck(cards: Vec<u64>, deck: &str) -> Self {
+        Request::new(
+            "changeDeck",
+            Params::ChangeDeck {
+                cards,
+                deck: deck.to_string(),
+            },
+        )
+    }
+
+    pub fn version() -> Self {
+        Request::new("version", Params::None {})
+    }
+
+    pub fn find_notes(query: &str) -> Self {
+        Request::new(
+            "findNotes",
+            Params::Query {
+                query: query.to_string(),
+            },
+        )
+    }
+
+    pub fn store_media_file(filename: &str, data: &str) -> Self {
+        Request::new(
+            "storeMediaFile",
+            Params::Media {
+                filename: filename.to_string(),
+                data: data.to_string(),
+            },
+        )
+    }
+
+    pub fn deck_names() -> Self {
+        Request::new("deckNames", Params::None {})
+    }
+
+    pub fn create_deck(deck: &str) -> Self {
+        Request::new(
+            "createDeck",
+            Params::Deck {
+                deck: deck.to_string(),
+            },
+        )
+    }
+
+    /// Bundles `requests` into a single `multi` request, answered with one response
+    /// per request.
+    pub fn multi(requests: &[Request]) -> Self {
+        Request::new(
+            "multi",
+            Params::Multi {
+                actions: requests.to_vec(),
+            },
+        )
+    }
+
+    pub fn model_field_names(model_name: &str) -> Self {
+        Request::new(
+            "modelFieldNames",
+            Params::Model {
+                model_name: model_name.to_string(),
+            },
+        )
+    }
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Params {
+    Note {
+        note: Note,
+    },
+    Model {
+        #[serde(rename = "modelName")]
+        model_name: String,
+    },
+    ChangeDeck {
+        cards: Vec<u64>,
+        deck: String,
+    },
+    /// Tags are separated by spaces, which Anki does not allow inside a tag.
+    Tags {
+        notes: Vec<u64>,
+        tags: String,
+    },
+    Notes {
+        notes: Vec<u64>,
+    },
+    Cards {
+        cards: Vec<u64>,
+    },
+    Deck {
+        deck: String,
+    },
+    Query {
+        query: String,
+    },
+    Media {
+        filename: String,
+        data: String,
+    },
+    Multi {
+        actions: Vec<Request>,
+    },
+    None {},
+}
+
+/// A `key:value` term of an Anki search that matches `value` literally, such as
+/// `"Front:a\_b"`.
+pub fn search_term(key: &str, value: &str) -> String {
+    let mut term = format!("\"{}:", key);
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_') {
+            term.push('\\');
+        }
+        term.push(c);
+    }
+    term.push('"');
+    term
+}
+
+/// An Anki search for the notes with cards in `deck` itself. `deck:` alone also
+/// matches the subdecks of `deck`, which are left out with an unescaped `*`.
+pub fn deck_search(deck: &str) -> String {
+    let mut subdecks = search_term("deck", &format!("{}::", deck));
+    subdecks.insert(subdecks.len() - 1, '*');
+    format!("{} -{}", search_term("deck", deck), subdecks)
+}
+
+/// `text` without its HTML tags, which Anki leaves out when it compares first fields
+/// to find duplicates.
+pub fn strip_html(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => stripped.push(c),
+            _ => {}
+        }
+    }
+    stripped
+}
+
+/// Field contents of a note, keyed by field name.
+pub type NoteFields = BTreeMap<String, String>;
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Note {
     pub id: Option<u64>,
     pub deck_name: String,
     pub model_name: String,
-    pub fields: BasicModelFields,
+    pub fields: NoteFields,
+    /// Left out when empty, so that `updateNote` keeps the tags of the note.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<NoteOptions>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct BasicModelFields {
-    #[serde(rename = "Front")]
-    pub front: String,
-    #[serde(rename = "Back")]
-    pub back: String,
+/// Options of `addNote`.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NoteOptions {
+    /// Create the note even when one with the same first field exists.
+    pub allow_duplicate: bool,
+    /// Where Anki looks for a note with the same first field, the whole collection
+    /// when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_scope: Option<DuplicateScope>,
+}
+
+/// Where `addNote` looks for notes with the same first field.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateScope {
+    /// Only the deck the note is added to.
+    Deck,
+    Collection,
+}
+
+/// An entry of the `notesInfo` result. Anki answers with an empty object for notes that
+/// do not exist, which leaves `note_id` at 0.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NoteInfo {
+    pub note_id: u64,
+    pub model_name: String,
+    pub tags: Vec<String>,
+    pub fields: BTreeMap<String, NoteFieldInfo>,
+    pub cards: Vec<u64>,
+    /// Time of the last change to the note, in seconds since the epoch.
+    #[serde(rename = "mod")]
+    pub modified: u64,
+}
+
+impl NoteInfo {
+    /// Names of the fields in the order the note type defines them.
+    pub fn field_names(&self) -> Vec<String> {
+        let mut fields: Vec<(&String, &NoteFieldInfo)> = self.fields.iter().collect();
+        fields.sort_by_key(|(_, field)| field.order);
+        fields.into_iter().map(|(name, _)| name.clone()).collect()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct NoteFieldInfo {
+    pub value: String,
+    pub order: u32,
+}
+
+/// An entry of the `cardsInfo` result.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CardInfo {
+    pub card_id: u64,
+    pub deck_name: String,
+    pub note: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Response {
-    pub result: Option<u64>,
+/// The body of an AnkiConnect answer. `result` is `None` when the action failed or
+/// has nothing to return.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Response<T = serde_json::Value> {
+    pub result: Option<T>,
     pub error: Option<String>,
 }
+
+impl<T> Response<T> {
+    pub fn into_result(self) -> Result<Option<T>, AnkiError> {
+        match self.error {
+            Some(error) => Err(AnkiError::Anki(error)),
+            None => Ok(self.result),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnkiError {
+    /// Anki could not be reached, usually because it is not running.
+    Connection(String),
+    /// Anki answered with something that is not a valid AnkiConnect response.
+    Protocol(String),
+    /// AnkiConnect reported an error for the request.
+    Anki(String),
+}
+
+impl fmt::Display for AnkiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnkiError::Connection(e) => write!(f, "Cannot connect to Anki: {}", e),
+            AnkiError::Protocol(e) => write!(f, "Unexpected response from Anki: {}", e),
+            AnkiError::Anki(e) => write!(f, "Anki error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AnkiError {}
+
+/// Carries requests to AnkiConnect and brings back its raw responses.
+pub trait Transport {
+    fn send(&self, request: &Request) -> Result<Response, AnkiError>;
+}
+
+/// The AnkiConnect actions the sync uses. Every `Transport` is a client.
+pub trait AnkiClient {
+    fn version(&self) -> Result<u64, AnkiError>;
+    fn deck_names(&self) -> Result<Vec<String>, AnkiError>;
+    fn create_deck(&self, deck: &str) -> Result<u64, AnkiError>;
+    fn model_field_names(&self, model_name: &str) -> Result<Vec<String>, AnkiError>;
+    fn add_note(&self, note: &Note) -> Result<u64, AnkiError>;
+    fn update_note(&self, note: &Note) -> Result<(), AnkiError>;
+    fn add_tags(&self, notes: &[u64], tags: &[String]) -> Result<(), AnkiError>;
+    fn remove_tags(&self, notes: &[u64], tags: &[String]) -> Result<(), AnkiError>;
+    fn notes_info(&self, notes: &[u64]) -> Result<Vec<NoteInfo>, AnkiError>;
+    fn cards_info(&self, cards: &[u64]) -> Result<Vec<CardInfo>, AnkiError>;
+    fn find_notes(&self, query: &str) -> Result<Vec<u64>, AnkiError>;
+    fn change_deck(&self, cards: &[u64], deck: &str) -> Result<(), AnkiError>;
+    /// Suspends the cards. Returns whether any of them was not suspended yet.
+    fn suspend(&self, cards: &[u64]) -> Result<bool, AnkiError>;
+    fn delete_notes(&self, notes: &[u64]) -> Result<(), AnkiError>;
+    /// Stores a file in the media folder of Anki and returns the name it was stored
+    /// under. `data` is the base64 encoded content.
+    fn store_media_file(&self, filename: &str, data: &str) -> Result<String, AnkiError>;
+    /// Sends `requests` in a single `multi` request. The outer error is a failure of
+    /// the whole batch, the inner ones belong to a single request.
+    fn multi(
+        &self,
+        requests: &[Request],
+    ) -> Result<Vec<Result<serde_json::Value, AnkiError>>, AnkiError>;
+}
+
+/// Sends `request` and reads its result as `R`. A missing result reads as `null`, which
+/// suits actions that return nothing.
+fn call<R: DeserializeOwned>(
+    transport: &(impl Transport + ?Sized),
+    request: &Request,
+) -> Result<R, AnkiError> {
+    let result = transport.send(request)?.into_result()?;
+    serde_json::from_value(result.unwrap_or_default()).map_err(|e| {
+        AnkiError::Protocol(format!("unexpected result of '{}': {}", request.action, e))
+    })
+}
+
+impl<T: Transport + ?Sized> AnkiClient for T {
+    fn version(&self) -> Result<u64, AnkiError> {
+        call(self, &Request::version())
+    }
+
+    fn deck_names(&self) -> Result<Vec<String>, AnkiError> {
+        call(self, &Request::deck_names())
+    }
+
+    fn create_deck(&self, deck: &str) -> Result<u64, AnkiError> {
+        call(self, &Request::create_deck(deck))
+    }
+
+    fn model_field_names(&self, model_name: &str) -> Result<Vec<String>, AnkiError> {
+        call(self, &Request::model_field_names(model_name))
+    }
+
+    fn add_note(&self, note: &Note) -> Result<u64, AnkiError> {
+        call(self, &Request::add_note(note.clone()))
+    }
+
+    fn update_note(&self, note: &Note) -> Result<(), AnkiError> {
+        call(self, &Request::update_note(note.clone()))
+    }
+
+    fn add_tags(&self, notes: &[u64], tags: &[String]) -> Result<(), AnkiError> {
+        call(self, &Request::add_tags(notes.to_vec(), tags))
+    }
+
+    fn remove_tags(&self, notes: &[u64], tags: &[String]) -> Result<(), AnkiError> {
+        call(self, &Request::remove_tags(notes.to_vec(), tags))
+    }
+
+    fn notes_info(&self, notes: &[u64]) -> Result<Vec<NoteInfo>, AnkiError> {
+        call(self, &Request::notes_info(notes.to_vec()))
+    }
+
+    fn cards_info(&self, cards: &[u64]) -> Result<Vec<CardInfo>, AnkiError> {
+        call(self, &Request::cards_info(cards.to_vec()))
+    }
+
+    fn find_notes(&self, query: &str) -> Result<Vec<u64>, AnkiError> {
+        call(self, &Request::find_notes(query))
+    }
+
+    fn change_deck(&self, cards: &[u64], deck: &str) -> Result<(), AnkiError> {
+        call(self, &Request::change_deck(cards.to_vec(), deck))
+    }
+
+    fn suspend(&self, cards: &[u64]) -> Result<bool, AnkiError> {
+        call(self, &Request::suspend(cards.to_vec()))
+    }
+
+    fn delete_notes(&self, notes: &[u64]) -> Result<(), AnkiError> {
+        call(self, &Request::delete_notes(notes.to_vec()))
+    }
+
+    fn store_media_file(&self, filename: &str, data: &str) -> Result<String, AnkiError> {
+        call(self, &Request::store_media_file(filename, data))
+    }
+
+    fn multi(
+        &self,
+        requests: &[Request],
+    ) -> Result<Vec<Result<serde_json::Value, AnkiError>>, AnkiError> {
+        let responses: Vec<Response> = call(self, &Request::multi(requests))?;
+        if responses.len() != requests.len() {
+            return Err(AnkiError::Protocol(format!(
+                "expected {} results in the batch, got {}",
+                requests.len(),
+                responses.len()
+            )));
+        }
+        Ok(responses
+            .into_iter()
+            .map(|response| Ok(response.into_result()?.unwrap_or_default()))
+            .collect())
+    }
+}
@@ -0,0 +1,533 @@
+use super::{
+    AnkiError, CardInfo, DuplicateScope, Note, NoteFieldInfo, NoteInfo, Params, Request, Response,
+    Transport, strip_html,
+};
+use serde_json::{Value, json};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+type Hook = Box<dyn Fn(&Request) -> Option<AnkiError>>;
+
+/// An in-memory Anki that answers requests like AnkiConnect does. It starts out with
+/// the `Default` deck and the stock `Basic` and `Cloze` note types. Every note has a
+/// single card whose ID is ten times the note ID. Modification times come from a clock
+/// that ticks on every change to a note.
+#[derive(Default)]
+pub struct FakeAnki {
+    collection: RefCell<Collection>,
+    requests: RefCell<Vec<Request>>,
+    hooks: RefCell<Vec<Hook>>,
+}
+
+#[derive(Debug)]
+struct Collection {
+    next_id: u64,
+    decks: BTreeSet<String>,
+    models: BTreeMap<String, Vec<String>>,
+    notes: BTreeMap<u64, Note>,
+    modified: BTreeMap<u64, u64>,
+    clock: u64,
+    cards: BTreeMap<u64, CardInfo>,
+    suspended: BTreeSet<u64>,
+    media: BTreeMap<String, String>,
+}
+
+impl Default for Collection {
+    fn default() -> Self {
+        let model = |fields: &[&str]| fields.iter().map(|f| f.to_string()).collect();
+        Collection {
+            next_id: 1,
+            decks: BTreeSet::from(["Default".to_string()]),
+            models: BTreeMap::from([
+                ("Basic".to_string(), model(&["Front", "Back"])),
+                ("Cloze".to_string(), model(&["Text", "Back Extra"])),
+            ]),
+            notes: BTreeMap::new(),
+            modified: BTreeMap::new(),
+            clock: 1_700_000_000,
+            cards: BTreeMap::new(),
+            suspended: BTreeSet::new(),
+            media: BTreeMap::new(),
+        }
+    }
+}
+
+impl FakeAnki {
+    pub fn new() -> Self {
+        FakeAnki::default()
+    }
+
+    pub fn add_model(&self, name: &str, fields: &[&str]) {
+        self.collection.borrow_mut().models.insert(
+            name.to_string(),
+            fields.iter().map(|f| f.to_string()).collect(),
+        );
+    }
+
+    /// Puts a note into the collection as if it had been created earlier, keeping its
+    /// ID when it has one. Returns the ID of the note.
+    pub fn insert_note(&self, note: Note) -> u64 {
+        self.collection.borrow_mut().insert(note)
+    }
+
+    /// Changes a field of a note as if it had been edited in Anki.
+    pub fn edit_note(&self, id: u64, field: &str, value: &str) {
+        let mut collection = self.collection.borrow_mut();
+        if let Some(note) = collection.notes.get_mut(&id) {
+            note.fields.insert(field.to_string(), value.to_string());
+            collection.touch(id);
+        }
+    }
+
+    pub fn note(&self, id: u64) -> Option<Note> {
+        self.collection.borrow().notes.get(&id).cloned()
+    }
+
+    /// The decks the cards of a note are in.
+    pub fn note_decks(&self, id: u64) -> Vec<String> {
+        let collection = self.collection.borrow();
+        collection
+            .cards
+            .values()
+            .filter(|card| card.note == id)
+            .map(|card| card.deck_name.clone())
+            .collect()
+    }
+
+    pub fn is_suspended(&self, card: u64) -> bool {
+        self.collection.borrow().suspended.contains(&card)
+    }
+
+    pub fn decks(&self) -> BTreeSet<String> {
+        self.collection.borrow().decks.clone()
+    }
+
+    pub fn media(&self, filename: &str) -> Option<String> {
+        self.collection.borrow().media.get(filename).cloned()
+    }
+
+    /// Every request sent so far. The requests of a `multi` batch are only listed
+    /// inside it.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.borrow().clone()
+    }
+
+    /// Makes every request, including those inside a `multi` batch, for which `hook`
+    /// returns an error fail with that error.
+    pub fn fail_when(&self, hook: impl Fn(&Request) -> Option<AnkiError> + 'static) {
+        self.hooks.borrow_mut().push(Box::new(hook));
+    }
+
+    fn hook_error(&self, request: &Request) -> Option<AnkiError> {
+        self.hooks.borrow().iter().find_map(|hook| hook(request))
+    }
+
+    fn answer(&self, request: &Request) -> Result<Value, AnkiError> {
+        if let Some(error) = self.hook_error(request) {
+            return Err(error);
+        }
+        let mut collection = self.collection.borrow_mut();
+        match (request.action.as_str(), &request.params) {
+            ("version", _) => Ok(json!(6)),
+            ("deckNames", _) => Ok(json!(collection.decks)),
+            ("createDeck", Params::Deck { deck }) => {
+                collection.decks.insert(deck.clone());
+                Ok(json!(collection.decks.len()))
+            }
+            ("modelFieldNames", Params::Model { model_name }) => collection
+                .models
+                .get(model_name)
+                .map(|fields| json!(fields))
+                .ok_or_else(|| anki_error(format!("model was not found: {}", model_name))),
+            ("addNote", Params::Note { note }) => collection.add(note).map(|id| json!(id)),
+            ("updateNote", Params::Note { note }) => collection.update(note).map(|_| Value::Null),
+            ("addTags", Params::Tags { notes, tags }) => {
+                for id in notes {
+                    collection.change_tags(*id, |note_tags| {
+                        for tag in tags.split_whitespace() {
+                            if !note_tags.iter().any(|t| t == tag) {
+                                note_tags.push(tag.to_string());
+                            }
+                        }
+                    });
+                }
+                Ok(Value::Null)
+            }
+            ("removeTags", Params::Tags { notes, tags }) => {
+                for id in notes {
+                    collection.change_tags(*id, |note_tags| {
+                        note_tags.retain(|t| !tags.split_whitespace().any(|tag| tag == t))
+                    });
+                }
+                Ok(Value::Null)
+            }
+            ("notesInfo", Params::Notes { notes }) => {
+                let infos: Vec<NoteInfo> = notes
+                    .iter()
+                    .map(|id| collection.note_info(*id).unwrap_or_default())
+                    .collect();
+                Ok(json!(infos))
+            }
+            ("cardsInfo", Params::Cards { cards }) => {
+                let infos: Vec<&CardInfo> = cards
+                    .iter()
+                    .filter_map(|id| collection.cards.get(id))
+                    .collect();
+                Ok(json!(infos))
+            }
+            ("changeDeck", Params::ChangeDeck { cards, deck }) => {
+                for id in cards {
+                    if let Some(card) = collection.cards.get_mut(id) {
+                        card.deck_name = deck.clone();
+                    }
+                }
+                collection.decks.insert(deck.clone());
+                Ok(Value::Null)
+            }
+            ("suspend", Params::Cards { cards }) => {
+                let Collection {
+                    cards: known,
+                    suspended,
+                    ..
+                } = &mut *collection;
+                let mut changed = false;
+                for id in cards.iter().filter(|id| known.contains_key(id)) {
+                    changed |= suspended.insert(*id);
+                }
+                Ok(json!(changed))
+            }
+            ("deleteNotes", Params::Notes { notes }) => {
+                for id in notes {
+                    collection.notes.remove(id);
+                    collection.modified.remove(id);
+                    collection.cards.remove(&(id * 10));
+                }
+                Ok(Value::Null)
+            }
+            ("findNotes", Params::Query { query }) => collection.find(query).map(|ids| json!(ids)),
+            ("storeMediaFile", Params::Media { filename, data }) => {
+                collection.media.insert(filename.clone(), data.clone());
+                Ok(json!(filename))
+            }
+            (action, _) => Err(anki_error(format!("unsupported action: {}", action))),
+        }
+    }
+}
+
+fn anki_error(message: String) -> AnkiError {
+    AnkiError::Anki(message)
+}
+
+impl Collection {
+    fn insert(&mut self, mut note: Note) -> u64 {
+        let id = note.id.unwrap_or(self.next_id);
+        self.next_id = self.next_id.max(id + 1);
+        note.id = Some(id);
+        self.decks.insert(note.deck_name.clone());
+        self.cards.insert(
+            id * 10,
+            CardInfo {
+                card_id: id * 10,
+                deck_name: note.deck_name.clone(),
+                note: id,
+            },
+        );
+        self.notes.insert(id, note);
+        self.touch(id);
+        id
+    }
+
+    fn touch(&mut self, id: u64) {
+        self.clock += 1;
+        self.modified.insert(id, self.clock);
+    }
+
+    fn check_fields(&self, note: &Note) -> Result<(), AnkiError> {
+        let fields = self
+            .models
+            .get(&note.model_name)
+            .ok_or_else(|| anki_error(format!("model was not found: {}", note.model_name)))?;
+        match note.fields.keys().find(|name| !fields.contains(name)) {
+            Some(name) => Err(anki_error(format!(
+                "field '{}' is not in note type '{}'",
+                name, note.model_name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn add(&mut self, note: &Note) -> Result<u64, AnkiError> {
+        if !self.decks.contains(&note.deck_name) {
+            return Err(anki_error(format!(
+                "deck was not found: {}",
+                note.deck_name
+            )));
+        }
+        self.check_fields(note)?;
+        let first_name = self.models[&note.model_name].first();
+        let first_field = first_name
+            .and_then(|name| note.fields.get(name))
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| anki_error("cannot create note because it is empty".to_string()))?;
+        let options = note.options.clone().unwrap_or_default();
+        // Like Anki, the first fields are compared without their HTML.
+        let duplicate = self.notes.iter().any(|(id, other)| {
+            other.model_name == note.model_name
+                && first_name
+                    .and_then(|name| other.fields.get(name))
+                    .is_some_and(|other| strip_html(other) == strip_html(first_field))
+                && (options.duplicate_scope != Some(DuplicateScope::Deck)
+                    || self.cards[&(id * 10)].deck_name == note.deck_name)
+        });
+        let allowed = options.allow_duplicate;
+        if duplicate && !allowed {
+            return Err(anki_error(
+                "cannot create note because it is a duplicate".to_string(),
+            ));
+        }
+        Ok(self.insert(Note {
+            id: None,
+            ..note.clone()
+        }))
+    }
+
+    fn update(&mut self, note: &Note) -> Result<(), AnkiError> {
+        self.check_fields(note)?;
+        let id = note.id.unwrap_or_default();
+        let existing = self
+            .notes
+            .get_mut(&id)
+            .ok_or_else(|| anki_error(format!("Note was not found: {}", id)))?;
+        existing.fields.extend(note.fields.clone());
+        // Like AnkiConnect, a note sent without tags keeps its tags.
+        if !note.tags.is_empty() {
+            existing.tags = note.tags.clone();
+        }
+        self.touch(id);
+        Ok(())
+    }
+
+    fn change_tags(&mut self, id: u64, change: impl FnOnce(&mut Vec<String>)) {
+        if let Some(note) = self.notes.get_mut(&id) {
+            change(&mut note.tags);
+            self.touch(id);
+        }
+    }
+
+    fn note_info(&self, id: u64) -> Option<NoteInfo> {
+        let note = self.notes.get(&id)?;
+        let field_names = self.models.get(&note.model_name)?;
+        let fields = field_names
+            .iter()
+            .enumerate()
+            .map(|(order, name)| {
+                let value = note.fields.get(name).cloned().unwrap_or_default();
+                (
+                    name.clone(),
+                    NoteFieldInfo {
+                        value,
+                        order: order as u32,
+                    },
+                )
+            })
+            .collect();
+        Some(NoteInfo {
+            note_id: id,
+            model_name: note.model_name.clone(),
+            tags: note.tags.clone(),
+            fields,
+            cards: vec![id * 10],
+            modified: self.modified[&id],
+        })
+    }
+
+    /// Supports the queries the tool sends, made of `deck:`, `tag:`, `note:` and `nid:`
+    /// terms. Terms may be quoted, escaped and negated with `-`. Like in Anki, a deck
+    /// matches its subdecks, and a deck ending in an unescaped `*` every deck it starts.
+    fn find(&self, query: &str) -> Result<Vec<u64>, AnkiError> {
+        let unsupported = || anki_error(format!("unsupported query: {}", query));
+        let mut ids: Vec<u64> = self.notes.keys().copied().collect();
+        for term in search_terms(query) {
+            let (key, value) = term.text.split_once(':').ok_or_else(unsupported)?;
+            if !matches!(key, "deck" | "tag" | "note" | "nid") || (term.prefix && key != "deck") {
+                return Err(unsupported());
+            }
+            ids.retain(|id| {
+                let note = &self.notes[id];
+                let matches = match key {
+                    "deck" => {
+                        let deck = &self.cards[&(id * 10)].deck_name;
+                        deck == value
+                            || deck.starts_with(&format!("{}::", value))
+                            || (term.prefix && deck.starts_with(value))
+                    }
+                    "tag" => note.tags.iter().any(|tag| tag == value),
+                    "note" => note.model_name == value,
+                    _ => value.split(',').any(|nid| nid == id.to_string()),
+                };
+                matches != term.negated
+            });
+        }
+        Ok(ids)
+    }
+}
+
+/// A term of a search with its quotes and escapes removed.
+#[derive(Default)]
+struct SearchTerm {
+    text: String,
+    negated: bool,
+    /// Ends in an unescaped `*`.
+    prefix: bool,
+}
+
+/// The terms of a search, split on whitespace outside of quotes.
+fn search_terms(query: &str) -> Vec<SearchTerm> {
+    let mut terms = Vec::new();
+    let mut term = SearchTerm::default();
+    let mut quoted = false;
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => term.text.extend(chars.next()),
+            '"' => quoted = !quoted,
+            '*' => term.prefix = true,
+            '-' if term.text.is_empty() && !quoted => term.negated = true,
+            c if c.is_whitespace() && !quoted => {
+                if !term.text.is_empty() {
+                    terms.push(std::mem::take(&mut term));
+                }
+            }
+            c => term.text.push(c),
+        }
+    }
+    if !term.text.is_empty() {
+        terms.push(term);
+    }
+    terms
+}
+
+impl Transport for FakeAnki {
+    fn send(&self, request: &Request) -> Result<Response, AnkiError> {
+        self.requests.borrow_mut().push(request.clone());
+        let result = match &request.params {
+            Params::Multi { actions } => {
+                if let Some(error) = self.hook_error(request) {
+                    return Err(error);
+                }
+                let responses: Vec<Response> = actions
+                    .iter()
+                    .map(|action| match self.answer(action) {
+                        Ok(result) => response(Ok(result)),
+                        Err(AnkiError::Anki(error)) => response(Err(error)),
+                        Err(error) => response(Err(error.to_string())),
+                    })
+                    .collect();
+                Ok(json!(responses))
+            }
+            _ => self.answer(request),
+        };
+        match result {
+            Ok(result) => Ok(response(Ok(result))),
+            Err(AnkiError::Anki(error)) => Ok(response(Err(error))),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+fn response(result: Result<Value, String>) -> Response {
+    match result {
+        Ok(Value::Null) => Response {
+            result: None,
+            error: None,
+        },
+        Ok(result) => Response {
+            result: Some(result),
+            error: None,
+        },
+        Err(error) => Response {
+            result: None,
+            error: Some(error),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::{AnkiClient, deck_search, search_term};
+
+    fn basic_note(front: &str) -> Note {
+        Note {
+            id: None,
+            deck_name: "Default".to_string(),
+            model_name: "Basic".to_string(),
+            fields: BTreeMap::from([
+                ("Front".to_string(), front.to_string()),
+                ("Back".to_string(), "Back".to_string()),
+            ]),
+            tags: vec!["tag".to_string()],
+            options: None,
+        }
+    }
+
+    #[test]
+    fn test_notes_round_trip() {
+        let anki = FakeAnki::new();
+        let id = anki.add_note(&basic_note("Front")).unwrap();
+        assert_eq!(anki.note(id).unwrap().fields["Front"], "Front");
+        assert_eq!(anki.find_notes("tag:tag deck:Default").unwrap(), vec![id]);
+        assert_eq!(
+            anki.find_notes(&search_term("note", "Basic")).unwrap(),
+            vec![id]
+        );
+        assert_eq!(
+            anki.add_note(&basic_note("Front")),
+            Err(AnkiError::Anki(
+                "cannot create note because it is a duplicate".to_string()
+            ))
+        );
+
+        anki.change_deck(&[id * 10], "Other").unwrap();
+        assert_eq!(anki.note_decks(id), vec!["Other"]);
+        assert!(anki.deck_names().unwrap().contains(&"Other".to_string()));
+    }
+
+    #[test]
+    fn test_search_subdecks() {
+        let anki = FakeAnki::new();
+        let top = anki.add_note(&basic_note("Top")).unwrap();
+        let sub = anki.add_note(&basic_note("Sub")).unwrap();
+        anki.change_deck(&[top * 10], "Rust").unwrap();
+        anki.change_deck(&[sub * 10], "Rust::Traits").unwrap();
+        assert_eq!(anki.find_notes("deck:Rust").unwrap(), vec![top, sub]);
+        assert_eq!(anki.find_notes(&deck_search("Rust")).unwrap(), vec![top]);
+        assert!(anki.find_notes("Front:Top").is_err());
+    }
+
+    #[test]
+    fn test_multi_keeps_errors_per_request() {
+        let anki = FakeAnki::new();
+        let results = anki
+            .multi(&[
+                Request::add_note(basic_note("One")),
+                Request::model_field_names("Missing"),
+            ])
+            .unwrap();
+        assert_eq!(results[0], Ok(json!(1)));
+        assert_eq!(
+            results[1],
+            Err(AnkiError::Anki("model was not found: Missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_hook_fails_requests() {
+        let anki = FakeAnki::new();
+        anki.fail_when(|_| Some(AnkiError::Connection("refused".to_string())));
+        assert_eq!(
+            anki.version(),
+            Err(AnkiError::Connection("refused".to_string()))
+        );
+    }
+}
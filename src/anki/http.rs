@@ -0,0 +1,39 @@
+use super::{AnkiError, Request, Response, Transport};
+
+pub const DEFAULT_URL: &str = "http://localhost:8765";
+
+/// Talks to the AnkiConnect add-on over HTTP.
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    url: String,
+}
+
+impl HttpTransport {
+    pub fn new(url: &str) -> Self {
+        HttpTransport {
+            url: url.to_string(),
+        }
+    }
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        HttpTransport::new(DEFAULT_URL)
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, request: &Request) -> Result<Response, AnkiError> {
+        let mut body = ureq::post(&self.url)
+            .send_json(request)
+            .map_err(|e| match e {
+                ureq::Error::StatusCode(status) => {
+                    AnkiError::Protocol(format!("HTTP status {}", status))
+                }
+                e => AnkiError::Connection(format!("{} ({})", e, self.url)),
+            })?;
+        body.body_mut()
+            .read_json::<Response>()
+            .map_err(|e| AnkiError::Protocol(e.to_string()))
+    }
+}
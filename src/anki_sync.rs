@@ -1,10 +1,30 @@
-use crate::anki::{BasicModelFields, Note, Params, Request, Response};
-use crate::types::{BlankLine, Block, FlashCard, FlashCardMetaData, FrontMatter, MarkdownDocument};
+use crate::anki::{
+    AnkiClient, AnkiError, CardInfo, Note, NoteFields, NoteInfo, NoteOptions, Request, deck_search,
+    search_term, strip_html,
+};
+use crate::media::{MediaFile, localize_images};
+use crate::parser::cloze::{parse_cloze_number, parse_highlight};
+use crate::parser::field_section::parse_field_sections;
+use crate::render::math::convert_math;
+use crate::render::{RenderMode, render_block, render_inline};
+use crate::tags::{SOURCE_TAG_PREFIX, file_source_tag, heading_tags};
+use crate::types::{
+    BlankLine, Block, FlashCardMetaData, FrontMatter, MarkdownDocument, SyncSettings,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::AddAssign;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[derive(Debug)]
 pub struct BlockSyncPlan {
     pub block: Block,
     pub action: Action,
+    /// Hash of the planned note, recorded in its metadata once synced.
+    pub hash: Option<String>,
+    /// Images the card references, which are stored in Anki with the note.
+    pub media: Vec<MediaFile>,
 }
 
 #[derive(Debug)]
@@ -14,242 +34,2644 @@ pub enum Action {
     DoNothing,
 }
 
+impl Action {
+    pub fn note(&self) -> Option<&Note> {
+        match self {
+            Action::CreateNote(note) | Action::UpdateNote(note) => Some(note),
+            Action::DoNothing => None,
+        }
+    }
+
+    fn note_mut(&mut self) -> Option<&mut Note> {
+        match self {
+            Action::CreateNote(note) | Action::UpdateNote(note) => Some(note),
+            Action::DoNothing => None,
+        }
+    }
+}
+
+const BASIC_MODEL: &str = "Basic";
+const CLOZE_MODEL: &str = "Cloze";
+
+/// Field names the question and answer of a card are planned under. When the note type
+/// has no field of that name, they go into its first and second field instead.
+const QUESTION_FIELDS: [&str; 2] = ["Front", "Text"];
+const ANSWER_FIELDS: [&str; 2] = ["Back", "Back Extra"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SyncSummary {
+    pub created: u32,
+    pub updated: u32,
+    /// Updated notes whose cards were moved to another deck.
+    pub moved: u32,
+    /// Notes left alone because they changed in both Anki and the Markdown.
+    pub conflicts: u32,
+    pub failed: u32,
+}
+
+impl AddAssign for SyncSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.created += other.created;
+        self.updated += other.updated;
+        self.moved += other.moved;
+        self.conflicts += other.conflicts;
+        self.failed += other.failed;
+    }
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Created: {}, Updated: {}, Moved: {}, Conflicts: {}, Failed: {}",
+            self.created, self.updated, self.moved, self.conflicts, self.failed
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockSyncError {
+    /// 1-based line of the block in the Markdown file.
+    pub line: usize,
+    pub front: String,
+    pub message: String,
+}
+
+impl fmt::Display for BlockSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} ({:?}): {}", self.line, self.front, self.message)
+    }
+}
+
+/// Outcome of a sync. Blocks that failed, or were not reached because the sync was
+/// interrupted, keep their original text in `document`.
+#[derive(Debug)]
+pub struct SyncReport {
+    pub document: MarkdownDocument,
+    pub summary: SyncSummary,
+    pub errors: Vec<BlockSyncError>,
+    /// Blocks that were synced in a way the user should know about, such as cards
+    /// whose note was deleted in Anki and that are no longer synced.
+    pub warnings: Vec<BlockSyncError>,
+    pub interrupted: bool,
+    /// Nothing was sent because a note was deleted in Anki and `OnDeleted::Stop` is set.
+    pub stopped: bool,
+}
+
+/// Settings that apply to every document of a run.
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    pub render: RenderMode,
+    /// Create decks the plan uses that do not exist in Anki yet.
+    pub create_decks: bool,
+    /// Number of notes sent to Anki in one `multi` request.
+    pub batch_size: usize,
+    /// Side kept for notes that changed in both Anki and the Markdown.
+    pub prefer: Option<Prefer>,
+    /// What happens to cards whose note was deleted in Anki.
+    pub on_deleted: OnDeleted,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            render: RenderMode::default(),
+            create_decks: true,
+            batch_size: 100,
+            prefer: None,
+            on_deleted: OnDeleted::default(),
+        }
+    }
+}
+
+/// Which side wins when a note changed in both Anki and the Markdown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prefer {
+    Markdown,
+    Anki,
+}
+
+/// What happens to a changed card whose note no longer exists in Anki.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OnDeleted {
+    /// Create the note again and write back its new ID.
+    Recreate,
+    /// Set `anki_sync: false` on the card and warn about it.
+    Unsync,
+    /// Leave the file unchanged and send nothing.
+    #[default]
+    Stop,
+}
+
+/// A note that changed in both Anki and the Markdown since the last sync.
+#[derive(Debug)]
+pub struct Conflict<'a> {
+    /// 1-based line of the block in the Markdown file.
+    pub line: usize,
+    pub front: &'a str,
+    pub note_id: u64,
+}
+
+const CONFLICT_MESSAGE: &str = "Changed in both Anki and the Markdown since the last sync";
+
+/// The notes the plan updates as they are in Anki, with their cards.
+#[derive(Debug, Default)]
+struct AnkiNotes {
+    infos: BTreeMap<u64, NoteInfo>,
+    cards: BTreeMap<u64, Vec<CardInfo>>,
+}
+
 #[derive(Debug)]
 pub struct DocumentSyncPlan {
     pub front_matter: Option<FrontMatter>,
+    /// The front matter settings, with the deck of `deck_from_path` resolved.
+    pub settings: SyncSettings,
     pub block_plans: Vec<BlockSyncPlan>,
+    /// Directory relative image paths are resolved against, usually the one of the
+    /// Markdown file.
+    pub media_dir: PathBuf,
+    pub options: SyncOptions,
 }
 
 impl DocumentSyncPlan {
-    pub fn from_document(doc: MarkdownDocument) -> Self {
+    /// Plans the sync of `doc`, read from the Markdown file at `path` below the directory
+    /// `root`, which `deck_from_path` takes the path relative to.
+    pub fn from_document(
+        doc: MarkdownDocument,
+        path: &Path,
+        root: &Path,
+        options: &SyncOptions,
+    ) -> Self {
+        let relative = root_relative_path(path, root);
+        let settings = SyncSettings::for_document(&doc.front_matter, &relative);
+        let media_dir = path.parent().unwrap_or(Path::new(""));
+        let source_tag = file_source_tag(path, root);
         let block_plans = doc
             .blocks
             .iter()
-            .map(|block| BlockSyncPlan::from_block(block.clone(), &doc.front_matter))
+            .zip(heading_tags(&doc))
+            .map(|(block, tag)| {
+                BlockSyncPlan::from_block(block.clone(), &settings, media_dir, options)
+                    .with_tag(tag.map(|tag| settings.prefixed_tag(&tag)))
+                    .with_tag(source_tag.clone())
+            })
             .collect();
 
         Self {
             front_matter: doc.front_matter.clone(),
+            settings,
             block_plans,
+            media_dir: media_dir.to_path_buf(),
+            options: options.clone(),
         }
     }
 
-    pub fn sync(
+    /// The summary a successful sync of this plan would report.
+    pub fn planned_summary(&self) -> SyncSummary {
+        self.block_plans
+            .iter()
+            .fold(SyncSummary::default(), |mut summary, block_plan| {
+                match block_plan.action {
+                    Action::CreateNote(_) => summary.created += 1,
+                    Action::UpdateNote(_) if block_plan.markdown_changed() => summary.updated += 1,
+                    Action::UpdateNote(_) | Action::DoNothing => {}
+                }
+                summary
+            })
+    }
+
+    /// 1-based line on which each block starts in the Markdown file.
+    pub fn block_start_lines(&self) -> Vec<usize> {
+        let mut line = 1 + self
+            .front_matter
+            .as_ref()
+            .map_or(0, |front_matter| front_matter.raw().lines().count());
+        self.block_plans
+            .iter()
+            .map(|block_plan| {
+                let start = line;
+                line += block_plan.block.raw().lines().count();
+                start
+            })
+            .collect()
+    }
+
+    /// Asks Anki for the field names of every note type the plan uses.
+    fn fetch_model_field_names(
+        &self,
+        client: &impl AnkiClient,
+    ) -> BTreeMap<String, Result<Vec<String>, String>> {
+        let models: BTreeSet<&str> = self
+            .block_plans
+            .iter()
+            .filter_map(|block_plan| block_plan.action.note())
+            .map(|note| note.model_name.as_str())
+            .collect();
+
+        models
+            .into_iter()
+            .map(|model| {
+                let names = client.model_field_names(model).map_err(|e| e.to_string());
+                (model.to_string(), names)
+            })
+            .collect()
+    }
+
+    /// Creates the decks the plan uses that are missing from Anki, including sub-decks
+    /// such as `Parent::Child`. Returns the error for every deck that could not be
+    /// created.
+    fn create_missing_decks(&self, client: &impl AnkiClient) -> BTreeMap<String, String> {
+        let decks: BTreeSet<&str> = self
+            .block_plans
+            .iter()
+            .filter_map(|block_plan| block_plan.action.note())
+            .map(|note| note.deck_name.as_str())
+            .collect();
+        if decks.is_empty() {
+            return BTreeMap::new();
+        }
+
+        let existing: BTreeSet<String> = match client.deck_names() {
+            Ok(existing) => existing.into_iter().collect(),
+            Err(e) => {
+                let error = format!("Cannot read deck names: {}", e);
+                return decks
+                    .into_iter()
+                    .map(|deck| (deck.to_string(), error.clone()))
+                    .collect();
+            }
+        };
+
+        decks
+            .into_iter()
+            .filter(|deck| !existing.contains(*deck))
+            .filter_map(|deck| {
+                client.create_deck(deck).err().map(|e| {
+                    (
+                        deck.to_string(),
+                        format!("Cannot create deck '{}': {}", deck, e),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Asks Anki for every note the plan updates and for their cards, so that changes
+    /// made in Anki are noticed and notes whose deck changed can be moved.
+    fn fetch_anki_notes(&self, client: &impl AnkiClient) -> Result<AnkiNotes, AnkiError> {
+        let note_ids: Vec<u64> = self
+            .block_plans
+            .iter()
+            .filter(|block_plan| block_plan.markdown_changed())
+            .filter_map(|block_plan| match &block_plan.action {
+                Action::UpdateNote(note) => note.id,
+                _ => None,
+            })
+            .collect();
+        if note_ids.is_empty() {
+            return Ok(AnkiNotes::default());
+        }
+
+        let notes = client.notes_info(&note_ids)?;
+        let card_ids: Vec<u64> = notes.iter().flat_map(|note| note.cards.clone()).collect();
+        let cards = client.cards_info(&card_ids)?;
+
+        let mut anki_notes = AnkiNotes {
+            infos: note_ids.into_iter().zip(notes).collect(),
+            cards: BTreeMap::new(),
+        };
+        for card in cards {
+            anki_notes.cards.entry(card.note).or_default().push(card);
+        }
+        Ok(anki_notes)
+    }
+
+    /// Syncs every block, sending the note requests in `multi` batches of
+    /// `SyncOptions::batch_size`. A failing block does not stop the sync: it keeps its
+    /// original text and is reported in `SyncReport::errors`. Once `interrupted` is set
+    /// no further batches are sent and the blocks they hold are left unchanged.
+    ///
+    /// Notes whose Markdown did not change since the last sync are not sent. Notes that
+    /// changed in both Anki and the Markdown are conflicts and left alone unless
+    /// `SyncOptions::prefer` picks a side.
+    pub fn sync(self, client: &impl AnkiClient, interrupted: &AtomicBool) -> SyncReport {
+        self.sync_resolving(client, interrupted, |_| None)
+    }
+
+    /// Like `sync`, but asks `resolve` which side to keep for every conflict that
+    /// `SyncOptions::prefer` does not settle. Conflicts it answers with `None` are left
+    /// alone.
+    pub fn sync_resolving(
         self,
-        send_request: impl Fn(&Request) -> Option<Response>,
-    ) -> Result<(MarkdownDocument, u32, u32), String> {
-        let block_count = self.block_plans.len();
-
-        let (blocks, created, updated) = self.block_plans.iter().fold(
-            (Vec::new(), 0u32, 0u32),
-            |(mut blocks, mut created, mut updated), block_plan| {
-                let request = block_plan.to_request_payload();
-                let response = request.as_ref().and_then(&send_request);
-                match block_plan.block_from_response(&response) {
-                    Ok(block) => {
-                        match &block_plan.action {
-                            Action::CreateNote(_) => created += 1,
-                            Action::UpdateNote(_) => updated += 1,
-                            Action::DoNothing => {}
-                        }
-                        blocks.push(block);
+        client: &impl AnkiClient,
+        interrupted: &AtomicBool,
+        mut resolve: impl FnMut(&Conflict) -> Option<Prefer>,
+    ) -> SyncReport {
+        let mut run = SyncRun::start(self, client);
+        let pending = run.plan_requests(&mut resolve);
+        let duplicates = run.send_notes(pending, interrupted, client);
+        run.adopt_duplicates(duplicates, interrupted, client);
+        reconcile_tags(
+            &run.plan.block_plans,
+            &mut run.outcomes,
+            &run.anki_notes,
+            &run.plan.settings,
+            run.plan.options.batch_size,
+            client,
+        );
+        // Moved before the modification times are read, which moving may change.
+        run.move_notes(client);
+        record_modification_times(&run.plan.block_plans, &mut run.outcomes, client);
+        run.report()
+    }
+}
+
+/// A sync in progress, handed from one phase of `sync_resolving` to the next.
+struct SyncRun {
+    plan: DocumentSyncPlan,
+    start_lines: Vec<usize>,
+    field_names: BTreeMap<String, Result<Vec<String>, String>>,
+    anki_notes: Result<AnkiNotes, AnkiError>,
+    deck_errors: BTreeMap<String, String>,
+    /// Blocks that are never sent get their outcome right away, the others once their
+    /// batch is answered. Blocks without an outcome were not reached.
+    outcomes: Vec<Option<Result<Block, String>>>,
+    conflicts: BTreeSet<usize>,
+    warnings: Vec<BlockSyncError>,
+    /// Updated notes whose cards were moved to another deck.
+    moved: BTreeSet<usize>,
+    /// Updated notes whose cards could not be moved, with the error.
+    move_errors: BTreeMap<usize, String>,
+    stopped: bool,
+    interrupted: bool,
+}
+
+impl SyncRun {
+    /// Reads what the plan needs from Anki and creates its missing decks.
+    fn start(plan: DocumentSyncPlan, client: &impl AnkiClient) -> Self {
+        let field_names = plan.fetch_model_field_names(client);
+        let anki_notes = plan.fetch_anki_notes(client);
+        // A sync that stops on a deleted note sends nothing, not even its decks.
+        let stops = plan.options.on_deleted == OnDeleted::Stop
+            && plan.block_plans.iter().any(|block_plan| {
+                block_plan.markdown_changed() && block_plan.deleted_note(&anki_notes).is_some()
+            });
+        let deck_errors = if plan.options.create_decks && !stops {
+            plan.create_missing_decks(client)
+        } else {
+            BTreeMap::new()
+        };
+        SyncRun {
+            start_lines: plan.block_start_lines(),
+            plan,
+            field_names,
+            anki_notes,
+            deck_errors,
+            outcomes: Vec::new(),
+            conflicts: BTreeSet::new(),
+            warnings: Vec::new(),
+            moved: BTreeSet::new(),
+            move_errors: BTreeMap::new(),
+            stopped: false,
+            interrupted: false,
+        }
+    }
+
+    /// Decides what happens to every block: left alone, failed, pulled from Anki after a
+    /// conflict, or sent with the returned requests, keyed by block index.
+    fn plan_requests(
+        &mut self,
+        resolve: &mut impl FnMut(&Conflict) -> Option<Prefer>,
+    ) -> Vec<(usize, Request)> {
+        let plan = &mut self.plan;
+        let mode = plan.settings.render.unwrap_or(plan.options.render);
+        let mut pending = Vec::new();
+        for (index, block_plan) in plan.block_plans.iter_mut().enumerate() {
+            let line = self.start_lines[index];
+            if !block_plan.markdown_changed() {
+                block_plan.action = Action::DoNothing;
+                self.outcomes.push(Some(Ok(block_plan.block.clone())));
+                continue;
+            }
+
+            if let Some(id) = block_plan.deleted_note(&self.anki_notes) {
+                let message = format!("Note {} was deleted in Anki", id);
+                match plan.options.on_deleted {
+                    OnDeleted::Recreate => block_plan.recreate_note(),
+                    OnDeleted::Unsync => {
+                        let warning = BlockSyncError {
+                            line,
+                            front: block_plan.front().unwrap_or_default().to_string(),
+                            message: format!("{}, the card is no longer synced", message),
+                        };
+                        self.warnings.push(warning);
+                        block_plan.action = Action::DoNothing;
+                        self.outcomes.push(Some(Ok(block_plan.unsynced_block())));
+                        continue;
                     }
-                    Err(err) => {
-                        eprintln!("Error syncing block: {}", err);
+                    OnDeleted::Stop => {
+                        self.stopped = true;
+                        self.outcomes.push(Some(Err(message)));
+                        continue;
                     }
                 }
-                (blocks, created, updated)
-            },
-        );
+            }
 
-        if blocks.len() == block_count {
-            Ok((
-                MarkdownDocument {
-                    front_matter: self.front_matter,
-                    blocks,
+            let changed_in_anki = block_plan
+                .check_deck(&self.deck_errors)
+                .and_then(|_| block_plan.check_media(line))
+                .and_then(|_| block_plan.anki_change(&self.anki_notes));
+            let (side, info) = match changed_in_anki {
+                Ok(None) => (Prefer::Markdown, None),
+                Ok(Some(info)) => {
+                    let conflict = Conflict {
+                        line,
+                        front: block_plan.front().unwrap_or_default(),
+                        note_id: info.note_id,
+                    };
+                    match plan.options.prefer.or_else(|| resolve(&conflict)) {
+                        Some(side) => (side, Some(info)),
+                        None => {
+                            self.conflicts.insert(index);
+                            self.outcomes.push(Some(Err(CONFLICT_MESSAGE.to_string())));
+                            continue;
+                        }
+                    }
+                }
+                Err(message) => {
+                    self.outcomes.push(Some(Err(message)));
+                    continue;
+                }
+            };
+
+            self.outcomes.push(match (side, info) {
+                (Prefer::Anki, Some(info)) => {
+                    let pulled = block_plan
+                        .pulled_block(info, &plan.settings, &plan.media_dir, &plan.options)
+                        .map(|block| block.unwrap_or_else(|| block_plan.recorded_block(info)));
+                    block_plan.action = Action::DoNothing;
+                    Some(pulled)
+                }
+                _ => match block_plan
+                    .map_note_fields(&self.field_names, mode, &plan.media_dir)
+                    .map(|_| block_plan.to_request_payload())
+                {
+                    Ok(Some(request)) => {
+                        pending.push((index, request));
+                        None
+                    }
+                    Ok(None) => Some(Ok(block_plan.block.clone())),
+                    Err(message) => Some(Err(message)),
                 },
-                created,
-                updated,
-            ))
-        } else {
-            Err("Error syncing blocks with Anki responses.".to_string())
+            });
         }
-    }
-}
 
-impl BlockSyncPlan {
-    pub fn from_block(block: Block, front_matter: &Option<FrontMatter>) -> Self {
-        let mut default_deck: Option<String> = None;
-        let mut default_tags: Vec<String> = Vec::new();
-
-        if let Some(FrontMatter::AnkiSync { raw: _, deck, tags }) = front_matter {
-            default_deck = deck.clone();
-            default_tags = tags.clone();
-        }
-
-        match block {
-            ref block @ Block::FlashCard(FlashCard {
-                raw: _,
-                ref front,
-                ref back,
-            }) => {
-                let note = Note {
-                    id: None,
-                    deck_name: default_deck.unwrap_or("Default".to_string()),
-                    model_name: "Basic".to_string(),
-                    fields: BasicModelFields {
-                        front: front.clone(),
-                        back: back.clone(),
-                    },
-                    tags: default_tags.clone(),
-                };
-
-                Self {
-                    block: block.clone(),
-                    action: Action::CreateNote(note),
+        // Stopping leaves every block that did not fail as it was.
+        if self.stopped {
+            pending.clear();
+            for outcome in &mut self.outcomes {
+                if matches!(outcome, Some(Ok(_))) {
+                    *outcome = None;
                 }
             }
+        }
+        pending
+    }
+
+    /// Uploads the images of the `pending` notes and sends the notes in batches, until
+    /// `interrupted` is set. Returns the blocks whose note Anki refused as a duplicate.
+    fn send_notes(
+        &mut self,
+        mut pending: Vec<(usize, Request)>,
+        interrupted: &AtomicBool,
+        client: &impl AnkiClient,
+    ) -> Vec<usize> {
+        let block_plans = &self.plan.block_plans;
+        let media_errors = upload_media(block_plans, &pending, client);
+        pending.retain(|(index, _)| {
+            let error = block_plans[*index]
+                .media
+                .iter()
+                .find_map(|file| media_errors.get(file.name.as_deref()?));
+            if let Some(error) = error {
+                self.outcomes[*index] = Some(Err(error.clone()));
+            }
+            error.is_none()
+        });
 
-            ref block @ Block::FlashCardWithMeta {
-                metadata:
-                    FlashCardMetaData {
-                        raw: _,
-                        id: Some(id),
-                        sync,
-                        ref deck,
-                        ref tags,
-                    },
-                blank_line: _,
-                flashcard:
-                    FlashCard {
-                        raw: _,
-                        ref front,
-                        ref back,
-                    },
-            } if sync.is_none_or(|x| x) => {
-                let deck_name = deck
-                    .clone()
-                    .or(default_deck)
-                    .unwrap_or("Default".to_string());
-                let tags = tags.clone().unwrap_or(default_tags);
-
-                let note = Note {
-                    id: Some(id),
-                    deck_name,
-                    model_name: "Basic".to_string(),
-                    fields: BasicModelFields {
-                        front: front.clone(),
-                        back: back.clone(),
-                    },
-                    tags,
-                };
-
-                Self {
-                    block: block.clone(),
-
-                    action: Action::UpdateNote(note),
+        let mut duplicates = Vec::new();
+        for batch in pending.chunks(self.plan.options.batch_size.max(1)) {
+            if interrupted.load(Ordering::SeqCst) {
+                self.interrupted = true;
+                break;
+            }
+            let (indices, requests): (Vec<usize>, Vec<Request>) = batch.iter().cloned().unzip();
+            for (index, result) in indices.into_iter().zip(send_batch(&requests, client)) {
+                if block_plans[index].is_duplicate(&result) {
+                    duplicates.push(index);
+                } else {
+                    self.outcomes[index] = Some(block_plans[index].block_from_result(result));
                 }
             }
+        }
+        duplicates
+    }
 
-            other => Self {
-                block: other,
-                action: Action::DoNothing,
-            },
+    /// Takes over the notes Anki refused as duplicates. Adopting a note sends more
+    /// requests, which a stopped or interrupted sync leaves out; the duplicates are then
+    /// not reached.
+    fn adopt_duplicates(
+        &mut self,
+        duplicates: Vec<usize>,
+        interrupted: &AtomicBool,
+        client: &impl AnkiClient,
+    ) {
+        if !duplicates.is_empty() && interrupted.load(Ordering::SeqCst) {
+            self.interrupted = true;
+        }
+        if self.stopped || self.interrupted {
+            return;
+        }
+        // The notes of a deck and note type are read once for all of its duplicates.
+        let mut in_deck = BTreeMap::new();
+        for index in duplicates {
+            let block_plan = &mut self.plan.block_plans[index];
+            let Action::CreateNote(note) = &block_plan.action else {
+                continue;
+            };
+            let key = (note.deck_name.clone(), note.model_name.clone());
+            let candidates = in_deck
+                .entry(key)
+                .or_insert_with(|| notes_in_deck(&note.deck_name, &note.model_name, client));
+            let adopted = match candidates {
+                Ok(candidates) => block_plan.adopt_duplicate(
+                    &self.field_names,
+                    candidates,
+                    &mut self.anki_notes,
+                    client,
+                ),
+                Err(message) => Err(message.clone()),
+            };
+            self.outcomes[index] = Some(adopted);
         }
     }
 
-    pub fn block_from_response(&self, response: &Option<Response>) -> Result<Block, String> {
-        match response {
-            Some(response) => match self {
-                // Create a note from flashcard
-                BlockSyncPlan {
-                    block: Block::FlashCard(FlashCard { raw, front, back }),
-                    action: Action::CreateNote(_),
-                } if response.result.is_some() && response.error.is_none() => {
-                    let id = response.result.unwrap();
-                    Ok(Block::FlashCardWithMeta {
-                        metadata: FlashCardMetaData::from_fields(Some(id), None, None, None),
-                        blank_line: Some(BlankLine::single()),
-                        flashcard: FlashCard {
-                            raw: raw.clone(),
-                            front: front.clone(),
-                            back: back.clone(),
-                        },
-                    })
+    /// Moves the cards of the updated notes to their planned deck. The notes were
+    /// updated even when they cannot be moved, so their blocks keep their new sync state
+    /// and only the move is reported.
+    fn move_notes(&mut self, client: &impl AnkiClient) {
+        for (index, (block_plan, outcome)) in
+            self.plan.block_plans.iter().zip(&self.outcomes).enumerate()
+        {
+            let (Action::UpdateNote(note), Some(Ok(_))) = (&block_plan.action, outcome) else {
+                continue;
+            };
+            match move_note(note, &self.anki_notes, client) {
+                Ok(true) => {
+                    self.moved.insert(index);
                 }
-                // Create a note from flashcard with metadata
-                BlockSyncPlan {
-                    block:
-                        Block::FlashCardWithMeta {
-                            metadata,
-                            blank_line,
-                            flashcard,
-                        },
-                    action: Action::CreateNote(_),
-                } if response.result.is_some() && response.error.is_none() => {
-                    let id = response.result.unwrap();
-                    Ok(Block::FlashCardWithMeta {
-                        metadata: FlashCardMetaData::from_fields(
-                            Some(id),
-                            metadata.deck.clone(),
-                            metadata.sync,
-                            metadata.tags.clone(),
-                        ),
-                        blank_line: blank_line.clone(),
-                        flashcard: FlashCard {
-                            raw: flashcard.raw.clone(),
-                            front: flashcard.front.clone(),
-                            back: flashcard.back.clone(),
-                        },
-                    })
+                Ok(false) => {}
+                Err(message) => {
+                    self.move_errors.insert(index, message);
                 }
-                // Update note from flashcard with metadata
-                BlockSyncPlan {
-                    block:
-                        Block::FlashCardWithMeta {
-                            metadata,
-                            blank_line,
-                            flashcard,
-                        },
-                    action: Action::UpdateNote(_),
-                } if response.result.is_none() && response.error.is_none() => {
-                    Ok(Block::FlashCardWithMeta {
-                        metadata: FlashCardMetaData::from_fields(
-                            metadata.id,
-                            metadata.deck.clone(),
-                            metadata.sync,
-                            metadata.tags.clone(),
-                        ),
-                        blank_line: blank_line.clone(),
-                        flashcard: FlashCard {
-                            raw: flashcard.raw.clone(),
-                            front: flashcard.front.clone(),
-                            back: flashcard.back.clone(),
-                        },
-                    })
+            }
+        }
+    }
+
+    /// The document with the outcome of every block, and what happened to them.
+    fn report(mut self) -> SyncReport {
+        let mut blocks = Vec::with_capacity(self.plan.block_plans.len());
+        let mut summary = SyncSummary::default();
+        let mut errors = Vec::new();
+        for (index, ((block_plan, line), outcome)) in self
+            .plan
+            .block_plans
+            .into_iter()
+            .zip(self.start_lines)
+            .zip(self.outcomes)
+            .enumerate()
+        {
+            let error = |message| BlockSyncError {
+                line,
+                front: block_plan.front().unwrap_or_default().to_string(),
+                message,
+            };
+            match outcome {
+                Some(Ok(block)) => {
+                    match &block_plan.action {
+                        Action::CreateNote(_) => summary.created += 1,
+                        Action::UpdateNote(_) => {
+                            summary.updated += 1;
+                            summary.moved += u32::from(self.moved.contains(&index));
+                            if let Some(message) = self.move_errors.remove(&index) {
+                                errors.push(error(message));
+                            }
+                        }
+                        Action::DoNothing => {}
+                    }
+                    blocks.push(block);
+                }
+                Some(Err(message)) => {
+                    if self.conflicts.contains(&index) {
+                        summary.conflicts += 1;
+                    } else {
+                        summary.failed += 1;
+                    }
+                    errors.push(error(message));
+                    blocks.push(block_plan.block);
                 }
-                _ => Err(format!(
-                    "Unexpected block or Anki response. Block: {:#?}, Response: {:#?}",
-                    self.block, response
-                )),
+                None => blocks.push(block_plan.block),
+            }
+        }
+
+        SyncReport {
+            document: MarkdownDocument {
+                front_matter: self.plan.front_matter,
+                blocks,
             },
-            _ => Ok(self.block.clone()),
+            summary,
+            errors,
+            warnings: self.warnings,
+            interrupted: self.interrupted,
+            stopped: self.stopped,
         }
     }
+}
 
-    pub fn to_request_payload(&self) -> Option<Request> {
+/// Stores the images of the notes about to be sent in the media folder of Anki, once
+/// per file. Returns the error for every image that could not be stored, keyed by the
+/// name it is stored under.
+fn upload_media(
+    block_plans: &[BlockSyncPlan],
+    pending: &[(usize, Request)],
+    client: &impl AnkiClient,
+) -> BTreeMap<String, String> {
+    let files: BTreeMap<&str, &MediaFile> = pending
+        .iter()
+        .flat_map(|(index, _)| &block_plans[*index].media)
+        .filter_map(|file| Some((file.name.as_deref()?, file)))
+        .collect();
+
+    files
+        .into_iter()
+        .filter_map(|(name, file)| {
+            let stored = file.data().and_then(|data| {
+                client
+                    .store_media_file(name, &data)
+                    .map_err(|e| e.to_string())
+            });
+            stored.err().map(|e| {
+                let error = format!("Cannot upload image '{}': {}", file.reference, e);
+                (name.to_string(), error)
+            })
+        })
+        .collect()
+}
+
+/// Sends `requests` as a single `multi` request and returns the result of each of them
+/// in order. A failure of the whole batch is returned for every request in it.
+fn send_batch(
+    requests: &[Request],
+    client: &impl AnkiClient,
+) -> Vec<Result<serde_json::Value, AnkiError>> {
+    match client.multi(requests) {
+        Ok(results) => results,
+        Err(error) => vec![Err(error); requests.len()],
+    }
+}
+
+/// Brings the tags of every updated note in line with the plan with `addTags` and
+/// `removeTags`, since `updateNote` is sent without them. The requests are sent in
+/// batches like the notes. Only tags the file manages are removed, so that tags added by
+/// hand in Anki survive. A note whose tags cannot be changed fails its block.
+fn reconcile_tags(
+    block_plans: &[BlockSyncPlan],
+    outcomes: &mut [Option<Result<Block, String>>],
+    anki_notes: &Result<AnkiNotes, AnkiError>,
+    settings: &SyncSettings,
+    batch_size: usize,
+    client: &impl AnkiClient,
+) {
+    let mut pending = Vec::new();
+    for (index, (block_plan, outcome)) in block_plans.iter().zip(outcomes.iter_mut()).enumerate() {
+        let (Action::UpdateNote(note), Some(Ok(_))) = (&block_plan.action, &outcome) else {
+            continue;
+        };
+        match tag_requests(note, anki_notes, settings) {
+            Ok(requests) => pending.extend(requests.into_iter().map(|request| (index, request))),
+            Err(message) => *outcome = Some(Err(message)),
+        }
+    }
+
+    for batch in pending.chunks(batch_size.max(1)) {
+        let (indices, requests): (Vec<usize>, Vec<Request>) = batch.iter().cloned().unzip();
+        let results = send_batch(&requests, client);
+        for ((index, request), result) in indices.into_iter().zip(&requests).zip(results) {
+            if let (Err(e), Some(Ok(_))) = (result, &outcomes[index]) {
+                outcomes[index] = Some(Err(match request.action.as_str() {
+                    "addTags" => format!("Cannot add tags to the note: {}", e),
+                    _ => format!("Cannot remove tags from the note: {}", e),
+                }));
+            }
+        }
+    }
+}
+
+/// The requests that give the note in Anki the tags of `note`.
+fn tag_requests(
+    note: &Note,
+    anki_notes: &Result<AnkiNotes, AnkiError>,
+    settings: &SyncSettings,
+) -> Result<Vec<Request>, String> {
+    let infos = &anki_notes
+        .as_ref()
+        .map_err(|e| format!("Cannot read the tags of the note: {}", e))?
+        .infos;
+    Ok(note
+        .id
+        .and_then(|id| infos.get(&id))
+        .map_or_else(Vec::new, |info| note_tag_requests(note, info, settings)))
+}
+
+/// The requests that give the note in Anki described by `info` the tags of `note`.
+fn note_tag_requests(note: &Note, info: &NoteInfo, settings: &SyncSettings) -> Vec<Request> {
+    // Anki tags ignore case, so `Rust` in Anki already is the `rust` of the Markdown.
+    let has_tag = |tags: &[String], tag: &str| {
+        let tag = tag.to_lowercase();
+        tags.iter().any(|other| other.to_lowercase() == tag)
+    };
+    let added: Vec<String> = note
+        .tags
+        .iter()
+        .filter(|tag| !has_tag(&info.tags, tag))
+        .cloned()
+        .collect();
+    let removed: Vec<String> = info
+        .tags
+        .iter()
+        .filter(|tag| settings.manages_tag(tag) && !has_tag(&note.tags, tag))
+        .cloned()
+        .collect();
+    let mut requests = Vec::new();
+    if !added.is_empty() {
+        requests.push(Request::add_tags(vec![info.note_id], &added));
+    }
+    if !removed.is_empty() {
+        requests.push(Request::remove_tags(vec![info.note_id], &removed));
+    }
+    requests
+}
+
+/// Writes the modification time in Anki into the metadata of every note that was sent,
+/// so that later changes made in Anki can be told apart. Notes whose time cannot be read
+/// are left without one.
+fn record_modification_times(
+    block_plans: &[BlockSyncPlan],
+    outcomes: &mut [Option<Result<Block, String>>],
+    client: &impl AnkiClient,
+) {
+    let sent: Vec<(usize, u64)> = outcomes
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| block_plans[*index].action.note().is_some())
+        .filter_map(|(index, outcome)| match outcome {
+            Some(Ok(block)) => block.metadata()?.id.map(|id| (index, id)),
+            _ => None,
+        })
+        .collect();
+    if sent.is_empty() {
+        return;
+    }
+
+    let ids: Vec<u64> = sent.iter().map(|(_, id)| *id).collect();
+    let Ok(infos) = client.notes_info(&ids) else {
+        return;
+    };
+    for ((index, _), info) in sent.into_iter().zip(infos) {
+        if let Some(Ok(block)) = &mut outcomes[index]
+            && info.note_id != 0
+        {
+            record_sync_state(block, None, Some(info.modified));
+        }
+    }
+}
+
+/// Sets the sync state in the metadata of `block`, keeping the parts given as `None`.
+pub(crate) fn record_sync_state(block: &mut Block, hash: Option<String>, modified: Option<u64>) {
+    if let Some(metadata) = block.metadata_mut() {
+        let metadata_before = std::mem::take(metadata);
+        *metadata = FlashCardMetaData {
+            hash: hash.or(metadata_before.hash.clone()),
+            modified: modified.or(metadata_before.modified),
+            ..metadata_before
+        }
+        .formatted();
+    }
+}
+
+/// The tags of a card: the file tags, or the `anki_tags` that replace them, with
+/// `anki_tags_add` added and `anki_tags_remove` removed.
+fn card_tags(metadata: Option<&FlashCardMetaData>, file_tags: &[String]) -> Vec<String> {
+    let mut tags = metadata
+        .and_then(|m| m.tags.clone())
+        .unwrap_or_else(|| file_tags.to_vec());
+    if let Some(metadata) = metadata {
+        for tag in metadata.tags_add.iter().flatten() {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+        let removed = metadata.tags_remove.as_deref().unwrap_or_default();
+        tags.retain(|tag| !removed.contains(tag));
+    }
+    tags
+}
+
+/// Hash of everything the Markdown decides about a note, to tell whether it changed
+/// since the last sync. FNV-1a keeps it stable across runs and platforms. The source tag
+/// is left out, so that cards synced before it existed, or whose file moved, do not all
+/// count as changed.
+pub(crate) fn content_hash(note: &Note) -> String {
+    let tags: Vec<&String> = note.tags.iter().filter(|tag| !is_source_tag(tag)).collect();
+    let content = serde_json::to_string(&(&note.deck_name, &note.model_name, &note.fields, tags))
+        .unwrap_or_default();
+    let hash = content
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// The notes of type `model` with cards in `deck` itself, leaving out its subdecks.
+fn notes_in_deck(
+    deck: &str,
+    model: &str,
+    client: &impl AnkiClient,
+) -> Result<Vec<NoteInfo>, String> {
+    let query = format!("{} {}", deck_search(deck), search_term("note", model));
+    let ids = client
+        .find_notes(&query)
+        .map_err(|e| format!("Cannot look for the duplicate note: {}", e))?;
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    client
+        .notes_info(&ids)
+        .map_err(|e| format!("Cannot look for the duplicate note: {}", e))
+}
+
+/// `updateNote` leaves a note in its deck, so cards that are not in the planned deck
+/// are moved with `changeDeck`. Returns whether any card was moved.
+fn move_note(
+    note: &Note,
+    anki_notes: &Result<AnkiNotes, AnkiError>,
+    client: &impl AnkiClient,
+) -> Result<bool, String> {
+    let note_cards = &anki_notes
+        .as_ref()
+        .map_err(|e| format!("Cannot read the deck of the note: {}", e))?
+        .cards;
+    let cards: Vec<u64> = note
+        .id
+        .and_then(|id| note_cards.get(&id))
+        .into_iter()
+        .flatten()
+        .filter(|card| card.deck_name != note.deck_name)
+        .map(|card| card.card_id)
+        .collect();
+    if cards.is_empty() {
+        return Ok(false);
+    }
+
+    client
+        .change_deck(&cards, &note.deck_name)
+        .map(|_| true)
+        .map_err(|e| format!("Cannot move the note to deck '{}': {}", note.deck_name, e))
+}
+
+/// The field of a note type a planned field goes into.
+pub(crate) fn field_target<'a>(name: &str, field_names: &'a [String]) -> Option<&'a String> {
+    if let Some(field) = field_names.iter().find(|field| *field == name) {
+        Some(field)
+    } else if QUESTION_FIELDS.contains(&name) {
+        field_names.first()
+    } else if ANSWER_FIELDS.contains(&name) {
+        field_names.get(1)
+    } else {
+        None
+    }
+}
+
+/// Moves the planned fields of a note to the fields of its note type, checking that
+/// every field exists.
+fn map_fields(
+    fields: &NoteFields,
+    model: &str,
+    field_names: &[String],
+) -> Result<NoteFields, String> {
+    let mut mapped = NoteFields::new();
+    for (name, content) in fields {
+        let target = field_target(name, field_names);
+        let target =
+            target.ok_or_else(|| format!("Note type '{}' has no field '{}'", model, name))?;
+        if mapped.insert(target.clone(), content.clone()).is_some() {
+            return Err(format!(
+                "Field '{}' of note type '{}' is set twice",
+                target, model
+            ));
+        }
+    }
+    Ok(mapped)
+}
+
+fn card_fields(
+    question: (&str, String),
+    answer: (&str, &str),
+    mode: RenderMode,
+    is_field: &dyn Fn(&str) -> bool,
+) -> NoteFields {
+    let (body, sections) = parse_field_sections(answer.1, is_field);
+    let mut fields = NoteFields::new();
+    fields.insert(question.0.to_string(), question.1);
+    fields.insert(answer.0.to_string(), render_block(&body, mode));
+    for section in sections {
+        fields.insert(section.name, render_block(&section.content, mode));
+    }
+    fields
+}
+
+/// The fields of the card in `block`, with the field sections whose name `is_field`
+/// accepts split out of the answer. `None` for passthrough blocks.
+fn block_fields(
+    block: &Block,
+    mode: RenderMode,
+    media_dir: &Path,
+    media: &mut Vec<MediaFile>,
+    is_field: &dyn Fn(&str) -> bool,
+) -> Option<NoteFields> {
+    // Relative images are replaced by the names they are stored under in Anki, and
+    // math gets the delimiters of Anki's MathJax.
+    let mut transform = |text: &str| convert_math(&localize_images(text, media_dir, media), mode);
+    match block {
+        Block::FlashCard(flashcard) | Block::FlashCardWithMeta { flashcard, .. } => {
+            let front = render_inline(&transform(&flashcard.front), mode);
+            let back = transform(&flashcard.back);
+            Some(card_fields(
+                ("Front", front),
+                ("Back", &back),
+                mode,
+                is_field,
+            ))
+        }
+        Block::Cloze(cloze) | Block::ClozeWithMeta { cloze, .. } => {
+            let text = render_inline(&cloze_deletions(&transform(&cloze.text)), mode);
+            let back_extra = transform(&cloze.back_extra);
+            Some(card_fields(
+                ("Text", text),
+                ("Back Extra", &back_extra),
+                mode,
+                is_field,
+            ))
+        }
+        Block::Passthrough(_) => None,
+    }
+}
+
+/// Turns every `==highlight==` into a cloze deletion numbered after the highest explicit
+/// `{{cN::...}}` deletion in the text.
+fn cloze_deletions(text: &str) -> String {
+    let highest = (0..text.len())
+        .filter(|&i| text.is_char_boundary(i))
+        .filter_map(|i| parse_cloze_number(&text[i..]).ok())
+        .map(|(_, number)| number)
+        .max()
+        .unwrap_or(0);
+
+    let mut next = highest + 1;
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match parse_highlight(rest) {
+            Ok((remaining, hidden)) => {
+                result.push_str(&format!("{{{{c{}::{}}}}}", next, hidden));
+                next += 1;
+                rest = remaining;
+            }
+            Err(_) => {
+                result.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    result
+}
+
+/// `path` relative to `root`, comparing their canonical paths so that the result does not
+/// depend on the working directory. `None` for a file outside of `root`.
+pub fn relative_path(path: &Path, root: &Path) -> Option<PathBuf> {
+    match (path.canonicalize(), root.canonicalize()) {
+        (Ok(path), Ok(root)) => path.strip_prefix(root).map(Path::to_path_buf).ok(),
+        _ => path.strip_prefix(root).map(Path::to_path_buf).ok(),
+    }
+}
+
+/// The path the deck and the source tag of the file at `path` are built from: the path
+/// relative to `root`, or only the name of a file outside of the root.
+pub fn root_relative_path(path: &Path, root: &Path) -> PathBuf {
+    relative_path(path, root)
+        .or_else(|| path.file_name().map(PathBuf::from))
+        .unwrap_or_default()
+}
+
+fn is_source_tag(tag: &str) -> bool {
+    tag.starts_with(&format!("{}::", SOURCE_TAG_PREFIX))
+}
+
+impl SyncSettings {
+    /// The settings of a document read from `path`, relative to the root of the sync, with
+    /// the deck of `deck_from_path` resolved. The cards of a file whose settings cannot
+    /// be read are left alone.
+    fn for_document(front_matter: &Option<FrontMatter>, path: &Path) -> Self {
+        let mut settings = match front_matter {
+            Some(FrontMatter::AnkiSync { settings, .. }) => settings.clone(),
+            Some(FrontMatter::Invalid { .. }) => SyncSettings {
+                sync: false,
+                ..SyncSettings::default()
+            },
+            Some(FrontMatter::Raw { .. }) | None => SyncSettings::default(),
+        };
+        if settings.deck_from_path {
+            let subdecks = path
+                .with_extension("")
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if !subdecks.is_empty() {
+                let deck: Vec<String> = settings.deck.into_iter().chain(subdecks).collect();
+                settings.deck = Some(deck.join("::"));
+            }
+        }
+        settings
+    }
+
+    /// Whether the sync may remove `tag` from a note: the tags naming a source file, and
+    /// the tags below the `tag_prefix` of the file. Without a prefix no other tag is
+    /// known to come from the Markdown, so none is removed.
+    fn manages_tag(&self, tag: &str) -> bool {
+        if is_source_tag(tag) {
+            return true;
+        }
+        self.tag_prefix
+            .as_ref()
+            .is_some_and(|prefix| tag == prefix || tag.starts_with(&format!("{}::", prefix)))
+    }
+
+    /// `tag` below the `tag_prefix` of the file, unless it already is.
+    pub fn prefixed_tag(&self, tag: &str) -> String {
+        match &self.tag_prefix {
+            Some(prefix) if tag != prefix && !tag.starts_with(&format!("{}::", prefix)) => {
+                format!("{}::{}", prefix, tag)
+            }
+            _ => tag.to_string(),
+        }
+    }
+}
+
+impl BlockSyncPlan {
+    pub fn from_block(
+        block: Block,
+        settings: &SyncSettings,
+        media_dir: &Path,
+        options: &SyncOptions,
+    ) -> Self {
+        let mode = settings.render.unwrap_or(options.render);
+        let mut media = Vec::new();
+        // Field names are not known before asking Anki, so every section is split out of
+        // the answer for now; see `model_fields`.
+        let Some(fields) = block_fields(&block, mode, media_dir, &mut media, &|_| true) else {
+            return Self {
+                block,
+                action: Action::DoNothing,
+                hash: None,
+                media,
+            };
+        };
+        let metadata = block.metadata();
+        // The front matter model only applies to basic cards; cloze cards need a cloze type.
+        let model = match &block {
+            Block::Cloze(_) | Block::ClozeWithMeta { .. } => None,
+            _ => settings.model.clone(),
+        };
+
+        if !settings.sync || metadata.and_then(|m| m.sync) == Some(false) {
+            return Self {
+                block,
+                action: Action::DoNothing,
+                hash: None,
+                media: Vec::new(),
+            };
+        }
+
+        let deck_name = metadata
+            .and_then(|m| m.deck.clone())
+            .or(settings.deck.clone())
+            .unwrap_or("Default".to_string());
+        let tags = card_tags(metadata, &settings.tags)
+            .iter()
+            .map(|tag| settings.prefixed_tag(tag))
+            .collect();
+        let allow_duplicate = metadata
+            .and_then(|m| m.allow_duplicate)
+            .unwrap_or(settings.allow_duplicates);
+        let duplicate_scope = metadata
+            .and_then(|m| m.duplicate_scope)
+            .or(settings.duplicate_scope);
+
+        let default_model = match block {
+            Block::Cloze(_) | Block::ClozeWithMeta { .. } => CLOZE_MODEL,
+            _ => BASIC_MODEL,
+        };
+        let model_name = metadata
+            .and_then(|m| m.model.clone())
+            .or(model)
+            .unwrap_or(default_model.to_string());
+
+        let note = Note {
+            id: metadata.and_then(|m| m.id),
+            deck_name,
+            model_name,
+            fields,
+            tags,
+            options: (allow_duplicate || duplicate_scope.is_some()).then_some(NoteOptions {
+                allow_duplicate,
+                duplicate_scope,
+            }),
+        };
+
+        let hash = Some(content_hash(&note));
+        let action = match note.id {
+            Some(_) => Action::UpdateNote(note),
+            None => Action::CreateNote(note),
+        };
+
+        Self {
+            block,
+            action,
+            hash,
+            media,
+        }
+    }
+
+    /// Adds a tag that comes from around the block, such as its headings, to the note.
+    fn with_tag(mut self, tag: Option<String>) -> Self {
+        if let (Some(tag), Some(note)) = (tag, self.action.note_mut()) {
+            if !note.tags.contains(&tag) {
+                note.tags.push(tag);
+            }
+            self.hash = Some(content_hash(note));
+        }
+        self
+    }
+
+    /// The ID of the note to update when Anki no longer has it.
+    fn deleted_note(&self, anki_notes: &Result<AnkiNotes, AnkiError>) -> Option<u64> {
+        let Action::UpdateNote(Note { id: Some(id), .. }) = &self.action else {
+            return None;
+        };
+        let info = anki_notes.as_ref().ok()?.infos.get(id)?;
+        (info.note_id == 0).then_some(*id)
+    }
+
+    /// Plans to create the note again instead of updating it. Its new ID replaces the
+    /// old one once it is created.
+    fn recreate_note(&mut self) {
+        if let Action::UpdateNote(note) = &self.action {
+            self.action = Action::CreateNote(Note {
+                id: None,
+                ..note.clone()
+            });
+        }
+    }
+
+    /// The block with `anki_sync: false` set in its metadata.
+    fn unsynced_block(&self) -> Block {
+        let mut block = self.block.clone();
+        if let Some(metadata) = block.metadata_mut() {
+            let metadata_before = std::mem::take(metadata);
+            *metadata = FlashCardMetaData {
+                sync: Some(false),
+                ..metadata_before
+            }
+            .formatted();
+        }
+        block
+    }
+
+    /// Whether the note differs from what the Markdown held at the last sync. Notes
+    /// synced before hashes were recorded count as changed.
+    pub fn markdown_changed(&self) -> bool {
+        self.hash.is_none()
+            || self.block.metadata().and_then(|m| m.hash.as_ref()) != self.hash.as_ref()
+    }
+
+    /// Fails when an image of the card cannot be read, naming the line it is on. `line`
+    /// is the line the block starts on.
+    fn check_media(&self, line: usize) -> Result<(), String> {
+        let Some(missing) = self.media.iter().find(|file| file.name.is_none()) else {
+            return Ok(());
+        };
+        let raw = self.block.raw();
+        let offset = raw
+            .find(&format!("]({}", missing.reference))
+            .map_or(0, |start| raw[..start].matches('\n').count());
+        Err(format!(
+            "Image '{}' on line {} was not found",
+            missing.reference,
+            line + offset
+        ))
+    }
+
+    /// The note in Anki when it was changed there since the last sync, going by the
+    /// modification time recorded in `anki_mod`. The Anki editor rewrites the HTML of a
+    /// field it only opens, so the fields are not compared with what the Markdown would
+    /// sync.
+    fn anki_change<'a>(
+        &self,
+        anki_notes: &'a Result<AnkiNotes, AnkiError>,
+    ) -> Result<Option<&'a NoteInfo>, String> {
+        let modified = self.block.metadata().and_then(|m| m.modified);
+        let (Action::UpdateNote(note), Some(modified)) = (&self.action, modified) else {
+            return Ok(None);
+        };
+        let anki_notes = anki_notes
+            .as_ref()
+            .map_err(|e| format!("Cannot read the note from Anki: {}", e))?;
+        let info = note.id.and_then(|id| anki_notes.infos.get(&id));
+        Ok(info.filter(|info| info.note_id != 0 && info.modified != modified))
+    }
+
+    /// The block with the hash of the note and the modification time of `info`
+    /// recorded, for notes where the Markdown and Anki are both kept as they are.
+    fn recorded_block(&self, info: &NoteInfo) -> Block {
+        let mut block = self.block.clone();
+        record_sync_state(&mut block, self.hash.clone(), Some(info.modified));
+        block
+    }
+
+    /// Front text of the flashcard this plan was made for.
+    pub fn front(&self) -> Option<&str> {
+        match &self.block {
+            Block::FlashCard(flashcard) | Block::FlashCardWithMeta { flashcard, .. } => {
+                Some(&flashcard.front)
+            }
+            Block::Cloze(cloze) | Block::ClozeWithMeta { cloze, .. } => Some(&cloze.text),
+            Block::Passthrough(_) => None,
+        }
+    }
+
+    /// Whether `result` is Anki refusing to create the note because it has one with the
+    /// same first field.
+    fn is_duplicate(&self, result: &Result<serde_json::Value, AnkiError>) -> bool {
+        matches!(self.action, Action::CreateNote(_))
+            && matches!(result, Err(AnkiError::Anki(message)) if message.contains("duplicate"))
+    }
+
+    /// Takes over the note among `in_deck`, the notes of the same type in the deck
+    /// itself, whose first field is the one of the note Anki refused to create as a
+    /// duplicate, and updates it instead. The note is added to `anki_notes`, so that its
+    /// tags and deck are synced like those of the other updated notes.
+    fn adopt_duplicate(
+        &mut self,
+        field_names: &BTreeMap<String, Result<Vec<String>, String>>,
+        in_deck: &[NoteInfo],
+        anki_notes: &mut Result<AnkiNotes, AnkiError>,
+        client: &impl AnkiClient,
+    ) -> Result<Block, String> {
+        let Action::CreateNote(note) = &self.action else {
+            return Ok(self.block.clone());
+        };
+        let first_field = field_names
+            .get(&note.model_name)
+            .and_then(|names| names.as_ref().ok()?.first())
+            .ok_or_else(|| format!("Unknown note type '{}'", note.model_name))?;
+        let front = note.fields.get(first_field).map_or("", String::as_str);
+        let first_value = |info: &NoteInfo| {
+            info.fields
+                .get(first_field)
+                .map_or(String::new(), |field| field.value.clone())
+        };
+
+        let Some(info) = in_deck.iter().find(|info| first_value(info) == front) else {
+            // Anki compares first fields without their HTML, so the note may be in the
+            // deck with the same text written differently.
+            let stripped = strip_html(front);
+            return Err(
+                match in_deck
+                    .iter()
+                    .find(|info| strip_html(&first_value(info)) == stripped)
+                {
+                    Some(info) => format!(
+                        "Anki has a note in deck '{}' whose {} only differs in its HTML: {:?}; \
+                         make them the same or set allow_duplicates or anki_allow_duplicate \
+                         to create it anyway",
+                        note.deck_name,
+                        first_field,
+                        first_value(info)
+                    ),
+                    None => format!(
+                        "Anki has a note with the same {} outside of deck '{}'; \
+                         set allow_duplicates or anki_allow_duplicate to create it anyway",
+                        first_field, note.deck_name
+                    ),
+                },
+            );
+        };
+        let cards = client
+            .cards_info(&info.cards)
+            .map_err(|e| format!("Cannot read the cards of the duplicate note: {}", e))?;
+
+        let note = Note {
+            id: Some(info.note_id),
+            ..note.clone()
+        };
+        let update = Note {
+            tags: Vec::new(),
+            ..note.clone()
+        };
+        client.update_note(&update).map_err(|e| e.to_string())?;
+        if let Ok(anki_notes) = anki_notes {
+            anki_notes.infos.insert(info.note_id, info.clone());
+            anki_notes.cards.insert(info.note_id, cards);
+        }
+        self.action = Action::UpdateNote(note);
+        self.synced_block(info.note_id, None)
+    }
+
+    /// Fails when the deck of the note could not be created.
+    fn check_deck(&self, deck_errors: &BTreeMap<String, String>) -> Result<(), String> {
+        match self
+            .action
+            .note()
+            .and_then(|note| deck_errors.get(&note.deck_name))
+        {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Renames the planned fields of the note to those of its note type.
+    fn map_note_fields(
+        &mut self,
+        field_names: &BTreeMap<String, Result<Vec<String>, String>>,
+        mode: RenderMode,
+        media_dir: &Path,
+    ) -> Result<(), String> {
+        let Some(note) = self.action.note() else {
+            return Ok(());
+        };
+        let names = field_names
+            .get(&note.model_name)
+            .ok_or_else(|| format!("Unknown note type '{}'", note.model_name))?
+            .as_ref()
+            .map_err(|e| format!("Cannot read note type '{}': {}", note.model_name, e))?;
+        let fields = map_fields(
+            &self.model_fields(names, mode, media_dir),
+            &note.model_name,
+            names,
+        )?;
+        if let Some(note) = self.action.note_mut() {
+            note.fields = fields;
+        }
+        Ok(())
+    }
+
+    /// The planned fields of the note for a note type with the fields `names`. Sections
+    /// of the answer named after no field of the type are kept in the answer.
+    pub(crate) fn model_fields(
+        &self,
+        names: &[String],
+        mode: RenderMode,
+        media_dir: &Path,
+    ) -> NoteFields {
+        let Some(note) = self.action.note() else {
+            return NoteFields::new();
+        };
+        if note
+            .fields
+            .keys()
+            .all(|name| field_target(name, names).is_some())
+        {
+            return note.fields.clone();
+        }
+        let is_field = |name: &str| names.iter().any(|field| field == name);
+        block_fields(&self.block, mode, media_dir, &mut Vec::new(), &is_field)
+            .unwrap_or_else(|| note.fields.clone())
+    }
+
+    /// The block as it is written back given the result of the request for its note.
+    pub fn block_from_result(
+        &self,
+        result: Result<serde_json::Value, AnkiError>,
+    ) -> Result<Block, String> {
+        let result = result.map_err(|e| match (&self.action, &e) {
+            (Action::UpdateNote(Note { id: Some(id), .. }), AnkiError::Anki(message))
+                if message.starts_with("Note was not found") =>
+            {
+                format!("Note {} was deleted in Anki", id)
+            }
+            _ => e.to_string(),
+        })?;
         match &self.action {
-            Action::CreateNote(note) => Some(Request {
-                action: "addNote".to_string(),
-                version: 6,
-                params: Params { note: note.clone() },
+            Action::CreateNote(_) => match result.as_u64() {
+                Some(id) => self.synced_block(id, None),
+                None => Err(format!("Unexpected note ID from Anki: {}", result)),
+            },
+            Action::UpdateNote(Note { id: Some(id), .. }) => self.synced_block(*id, None),
+            Action::UpdateNote(_) | Action::DoNothing => Ok(self.block.clone()),
+        }
+    }
+
+    /// The block as it is written back once its note exists in Anki under `id`, with
+    /// the hash of the note and the modification time recorded.
+    pub fn synced_block(&self, id: u64, modified: Option<u64>) -> Result<Block, String> {
+        self.block_with_metadata(|metadata| {
+            FlashCardMetaData {
+                id: Some(id),
+                hash: self.hash.clone(),
+                modified,
+                ..metadata.clone()
+            }
+            .formatted()
+        })
+    }
+
+    /// The block as it would be written back once its note is created, with
+    /// `placeholder` written in place of the ID Anki would assign.
+    pub fn created_block_preview(&self, placeholder: &str) -> Result<Block, String> {
+        self.block_with_metadata(|metadata| {
+            FlashCardMetaData {
+                id: None,
+                hash: self.hash.clone(),
+                ..metadata.clone()
+            }
+            .formatted_with_placeholder_id(placeholder)
+        })
+    }
+
+    /// The block with its metadata replaced by `metadata` applied to the current one,
+    /// adding a metadata comment to cards that have none.
+    fn block_with_metadata(
+        &self,
+        metadata: impl Fn(&FlashCardMetaData) -> FlashCardMetaData,
+    ) -> Result<Block, String> {
+        match &self.block {
+            // Create a note from flashcard
+            Block::FlashCard(flashcard) => Ok(Block::FlashCardWithMeta {
+                metadata: metadata(&FlashCardMetaData::default()),
+                blank_line: Some(BlankLine::single()),
+                flashcard: flashcard.clone(),
+            }),
+            // Create or update a note from flashcard with metadata
+            Block::FlashCardWithMeta {
+                metadata: existing,
+                blank_line,
+                flashcard,
+            } => Ok(Block::FlashCardWithMeta {
+                metadata: metadata(existing),
+                blank_line: blank_line.clone(),
+                flashcard: flashcard.clone(),
+            }),
+            // Create a note from cloze
+            Block::Cloze(cloze) => Ok(Block::ClozeWithMeta {
+                metadata: metadata(&FlashCardMetaData::default()),
+                blank_line: Some(BlankLine::single()),
+                cloze: cloze.clone(),
             }),
-            Action::UpdateNote(note) => Some(Request {
-                action: "updateNote".to_string(),
-                version: 6,
-                params: Params { note: note.clone() },
+            // Create or update a note from cloze with metadata
+            Block::ClozeWithMeta {
+                metadata: existing,
+                blank_line,
+                cloze,
+            } => Ok(Block::ClozeWithMeta {
+                metadata: metadata(existing),
+                blank_line: blank_line.clone(),
+                cloze: cloze.clone(),
             }),
+            other => Err(format!("Block cannot be synced: {:#?}", other)),
+        }
+    }
+
+    pub fn to_request_payload(&self) -> Option<Request> {
+        match &self.action {
+            Action::CreateNote(note) => Some(Request::add_note(note.clone())),
+            // The tags of updated notes are reconciled once the note is sent.
+            Action::UpdateNote(note) => Some(Request::update_note(Note {
+                tags: Vec::new(),
+                ..note.clone()
+            })),
             Action::DoNothing => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::Params;
+    use crate::anki::fake::FakeAnki;
+    use crate::parser::document::parse_document;
+    use indoc::indoc;
+    use std::sync::Arc;
+
+    fn plan(input: &str) -> DocumentSyncPlan {
+        let (_, doc) = parse_document(input).unwrap();
+        DocumentSyncPlan::from_document(doc, Path::new(""), Path::new(""), &SyncOptions::default())
+    }
+
+    /// A fake Anki that also knows a `Custom` note type.
+    fn anki() -> FakeAnki {
+        let anki = FakeAnki::new();
+        anki.add_model("Custom", &["Question", "Answer", "Hint"]);
+        anki
+    }
+
+    fn existing_note(anki: &FakeAnki, id: u64, front: &str) {
+        anki.insert_note(Note {
+            id: Some(id),
+            deck_name: "Default".to_string(),
+            model_name: "Basic".to_string(),
+            fields: NoteFields::from([("Front".to_string(), front.to_string())]),
+            tags: vec![],
+            options: None,
+        });
+    }
+
+    /// Makes requests with the `action` fail with the Anki error `message`.
+    fn fail_action(anki: &FakeAnki, action: &'static str, message: &'static str) {
+        anki.fail_when(move |request| {
+            (request.action == action).then(|| AnkiError::Anki(message.to_string()))
+        });
+    }
+
+    /// The requests sent with the `action`, outside of batches.
+    fn sent(anki: &FakeAnki, action: &str) -> Vec<Params> {
+        anki.requests()
+            .into_iter()
+            .filter(|request| request.action == action)
+            .map(|request| request.params)
+            .collect()
+    }
+
+    /// The requests sent with the `action` inside batches.
+    fn batched(anki: &FakeAnki, action: &str) -> Vec<Params> {
+        sent(anki, "multi")
+            .into_iter()
+            .flat_map(|params| match params {
+                Params::Multi { actions } => actions,
+                _ => Vec::new(),
+            })
+            .filter(|request| request.action == action)
+            .map(|request| request.params)
+            .collect()
+    }
+
+    fn planned_note(plan: &DocumentSyncPlan, index: usize) -> &Note {
+        plan.block_plans[index]
+            .action
+            .note()
+            .expect("Expected a note action")
+    }
+
+    #[test]
+    fn test_failed_block_keeps_original_text() {
+        let input = indoc! {"
+            ## Q: First?
+            Yes.
+
+            ## Q: Second?
+            No.
+
+            ## Q: Third?
+            Maybe.
+        "};
+        let anki = anki();
+        anki.fail_when(|request| match &request.params {
+            Params::Note { note } if note.fields["Front"] == "Second?" => {
+                Some(AnkiError::Anki("cannot create note".to_string()))
+            }
+            _ => None,
+        });
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(
+            report.summary,
+            SyncSummary {
+                created: 2,
+                updated: 0,
+                moved: 0,
+                conflicts: 0,
+                failed: 1
+            }
+        );
+        assert_eq!(
+            report.errors,
+            vec![BlockSyncError {
+                line: 4,
+                front: "Second?".to_string(),
+                message: "Anki error: cannot create note".to_string(),
+            }]
+        );
+        assert!(!report.interrupted);
+        assert_eq!(
+            report.document.raw(),
+            indoc! {"
+                <!-- anki_id: 1, anki_hash: 89c47270959ed8f1, anki_mod: 1700000001 -->
+
+                ## Q: First?
+                Yes.
+
+                ## Q: Second?
+                No.
+
+                <!-- anki_id: 2, anki_hash: baf77db401c56f13, anki_mod: 1700000002 -->
+
+                ## Q: Third?
+                Maybe.
+            "}
+        );
+    }
+
+    #[test]
+    fn test_failed_batch_fails_its_blocks() {
+        let input = indoc! {"
+            <!-- anki_id: 5 -->
+            ## Q: First?
+            Yes.
+        "};
+        let anki = anki();
+        existing_note(&anki, 5, "First?");
+        anki.fail_when(|request| {
+            (request.action == "multi").then(|| AnkiError::Connection("refused".to_string()))
+        });
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(report.errors[0].message, "Cannot connect to Anki: refused");
+        assert_eq!(report.document.raw(), input);
+    }
+
+    #[test]
+    fn test_interrupted_sync_keeps_remaining_blocks() {
+        let input = indoc! {"
+            ## Q: First?
+            Yes.
+
+            ## Q: Second?
+            No.
+        "};
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let anki = anki();
+        let flag = interrupted.clone();
+        anki.fail_when(move |request| {
+            if request.action == "addNote" {
+                flag.store(true, Ordering::SeqCst);
+            }
+            None
+        });
+        let mut plan = plan(input);
+        plan.options.batch_size = 1;
+        let report = plan.sync(&anki, &interrupted);
+
+        assert!(report.interrupted);
+        assert_eq!(report.summary.created, 1);
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            report.document.raw(),
+            indoc! {"
+                <!-- anki_id: 1, anki_hash: 89c47270959ed8f1, anki_mod: 1700000001 -->
+
+                ## Q: First?
+                Yes.
+
+                ## Q: Second?
+                No.
+            "}
+        );
+    }
+
+    #[test]
+    fn test_cloze_deletions_numbered_after_explicit_ones() {
+        assert_eq!(
+            cloze_deletions("==Paris== is in {{c2::France}}, ==Europe=="),
+            "{{c3::Paris}} is in {{c2::France}}, {{c4::Europe}}"
+        );
+        assert_eq!(cloze_deletions("==a== == b"), "{{c1::a}} == b");
+    }
+
+    #[test]
+    fn test_cloze_plan() {
+        let input = indoc! {"
+            <!-- anki_deck: Geo -->
+            ## C: The capital of ==France== is ==Paris==.
+            Since 987.
+        "};
+        let plan = plan(input);
+        let note = planned_note(&plan, 0);
+        assert!(matches!(plan.block_plans[0].action, Action::CreateNote(_)));
+        assert_eq!(note.model_name, "Cloze");
+        assert_eq!(note.deck_name, "Geo");
+        assert_eq!(
+            note.fields["Text"],
+            "The capital of {{c1::France}} is {{c2::Paris}}."
+        );
+        assert_eq!(note.fields["Back Extra"], "<p>Since 987.</p>\n");
+
+        let report = plan.sync(&anki(), &AtomicBool::new(false));
+        assert_eq!(report.summary.created, 1);
+        assert_eq!(
+            report.document.raw(),
+            indoc! {"
+                <!-- anki_deck: Geo, anki_id: 1, anki_hash: 9cd623fdc84ae869, anki_mod: 1700000001 -->
+                ## C: The capital of ==France== is ==Paris==.
+                Since 987.
+            "}
+        );
+    }
+
+    #[test]
+    fn test_card_with_metadata_but_no_id_created() {
+        let input = indoc! {"
+            <!-- anki_deck: Rust, anki_tags: [new] -->
+            ## Q: Created?
+            Yes.
+
+            <!-- anki_deck: Rust, anki_sync: false -->
+            ## Q: Skipped?
+            Yes.
+        "};
+        let plan = plan(input);
+        assert!(matches!(plan.block_plans[0].action, Action::CreateNote(_)));
+        assert!(matches!(plan.block_plans[1].action, Action::DoNothing));
+
+        let report = plan.sync(&anki(), &AtomicBool::new(false));
+        assert_eq!(report.summary.created, 1);
+        assert!(
+            report
+                .document
+                .raw()
+                .starts_with("<!-- anki_deck: Rust, anki_tags: [new], anki_id: 1, anki_hash: ")
+        );
+    }
+
+    #[test]
+    fn test_model_from_front_matter_and_metadata() {
+        let plan = plan(indoc! {"
+            ---
+            anki_sync:
+              model: Custom
+            ---
+            ## Q: From front matter?
+            Yes.
+
+            <!-- anki_model: Basic -->
+            ## Q: From metadata?
+            Yes.
+
+            ## C: Cloze cards keep the ==Cloze== type.
+        "});
+        assert_eq!(planned_note(&plan, 0).model_name, "Custom");
+        assert_eq!(planned_note(&plan, 1).model_name, "Basic");
+        assert_eq!(planned_note(&plan, 2).model_name, "Cloze");
+    }
+
+    #[test]
+    fn test_hierarchical_tags() {
+        let plan = plan(indoc! {"
+            ---
+            anki_sync:
+              tags: [lang::rust, spaced-repetition]
+              heading_tags: true
+            ---
+            # Rust Book
+            ## Q: What is ownership?
+            A set of rules.
+
+            <!-- anki_tags: [ch-4, lang::rust::ownership] -->
+            ## Q: Who drops a value?
+            Its owner.
+        "});
+        assert_eq!(
+            planned_note(&plan, 1).tags,
+            vec!["lang::rust", "spaced-repetition", "Rust_Book"]
+        );
+        assert_eq!(
+            planned_note(&plan, 2).tags,
+            vec!["ch-4", "lang::rust::ownership", "Rust_Book"]
+        );
+    }
+
+    #[test]
+    fn test_file_settings() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              deck: Notes
+              tags: [rust, notes::book]
+              render: raw
+              tag_prefix: notes
+              deck_from_path: true
+              allow_duplicates: true
+            ---
+            ## Q: What is *Rust*?
+            A language.
+        "};
+        let (_, doc) = parse_document(input).unwrap();
+        let plan = DocumentSyncPlan::from_document(
+            doc,
+            Path::new("./vault/rust/intro.md"),
+            Path::new("./vault"),
+            &SyncOptions::default(),
+        );
+        let note = planned_note(&plan, 0);
+        assert_eq!(note.deck_name, "Notes::rust::intro");
+        assert_eq!(
+            note.tags,
+            vec![
+                "notes::rust",
+                "notes::book",
+                "markdown-anki-sync::rust::intro.md"
+            ]
+        );
+        assert_eq!(note.fields["Front"], "What is *Rust*?");
+        assert_eq!(
+            note.options,
+            Some(NoteOptions {
+                allow_duplicate: true,
+                duplicate_scope: None
+            })
+        );
+        assert_eq!(plan.media_dir, Path::new("./vault/rust"));
+    }
+
+    #[test]
+    fn test_source_tag_left_out_of_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.md");
+        std::fs::write(&path, "").unwrap();
+        let (_, doc) = parse_document("## Q: Tagged?\nYes.\n").unwrap();
+        let tagged =
+            DocumentSyncPlan::from_document(doc, &path, dir.path(), &SyncOptions::default());
+        let note = planned_note(&tagged, 0);
+        assert_eq!(note.tags, vec!["markdown-anki-sync::notes.md"]);
+
+        let untagged = plan("## Q: Tagged?\nYes.\n");
+        assert!(planned_note(&untagged, 0).tags.is_empty());
+        assert_eq!(content_hash(note), content_hash(planned_note(&untagged, 0)));
+    }
+
+    #[test]
+    fn test_card_tags_added_to_file_tags() {
+        let plan = plan(indoc! {"
+            ---
+            anki_sync:
+              tags: [rust, book]
+            ---
+            <!-- anki_tags_add: [hard, rust] -->
+            ## Q: Added?
+            Yes.
+
+            <!-- anki_tags_remove: [book] -->
+            ## Q: Removed?
+            Yes.
+
+            <!-- anki_tags: [other], anki_tags_add: [hard], anki_tags_remove: [other] -->
+            ## Q: Replaced?
+            Yes.
+        "});
+        assert_eq!(planned_note(&plan, 0).tags, vec!["rust", "book", "hard"]);
+        assert_eq!(planned_note(&plan, 1).tags, vec!["rust"]);
+        assert_eq!(planned_note(&plan, 2).tags, vec!["hard"]);
+    }
+
+    #[test]
+    fn test_file_not_synced() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              sync: false
+            ---
+            <!-- anki_id: 1, anki_sync: true -->
+            ## Q: Skipped?
+            Yes.
+        "};
+        let plan = plan(input);
+        assert!(
+            plan.block_plans
+                .iter()
+                .all(|block_plan| matches!(block_plan.action, Action::DoNothing))
+        );
+        assert_eq!(
+            plan.sync(&anki(), &AtomicBool::new(false)).document.raw(),
+            input
+        );
+    }
+
+    #[test]
+    fn test_sections_mapped_to_custom_fields() {
+        let plan = plan(indoc! {"
+            <!-- anki_model: Custom -->
+            ## Q: What is `Rc`?
+            A reference-counted pointer.
+
+            ### Hint:
+            Counting.
+        "});
+        assert_eq!(
+            planned_note(&plan, 0).fields,
+            NoteFields::from([
+                ("Front".to_string(), "What is <code>Rc</code>?".to_string()),
+                (
+                    "Back".to_string(),
+                    "<p>A reference-counted pointer.</p>\n".to_string()
+                ),
+                ("Hint".to_string(), "<p>Counting.</p>\n".to_string()),
+            ])
+        );
+
+        let anki = anki();
+        let report = plan.sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.created, 1);
+        let fields = anki.note(1).unwrap().fields;
+        assert_eq!(
+            fields.keys().collect::<Vec<_>>(),
+            vec!["Answer", "Hint", "Question"]
+        );
+        assert_eq!(fields["Question"], "What is <code>Rc</code>?");
+    }
+
+    #[test]
+    fn test_section_of_unknown_field_kept_in_answer() {
+        let input = indoc! {"
+            ## Q: What is `Rc`?
+            A reference-counted pointer.
+
+            ### Example:
+            `Rc::new(5)`
+        "};
+        let anki = anki();
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.created, 1);
+        assert_eq!(
+            anki.note(1).unwrap().fields["Back"],
+            "<p>A reference-counted pointer.</p>\n<h3>Example:</h3>\n<p><code>Rc::new(5)</code></p>\n"
+        );
+    }
+
+    #[test]
+    fn test_unknown_model_fails_block() {
+        let input = indoc! {"
+            <!-- anki_model: Missing -->
+            ## Q: Which type?
+            None.
+        "};
+        let report = plan(input).sync(&anki(), &AtomicBool::new(false));
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(
+            report.errors[0].message,
+            "Cannot read note type 'Missing': Anki error: model was not found: Missing"
+        );
+    }
+
+    #[test]
+    fn test_note_moved_when_deck_changed() {
+        let input = indoc! {"
+            <!-- anki_id: 5, anki_deck: Rust -->
+            ## Q: Moved?
+            Yes.
+
+            <!-- anki_id: 6 -->
+            ## Q: Stays?
+            Yes.
+        "};
+        let anki = anki();
+        existing_note(&anki, 5, "Moved?");
+        existing_note(&anki, 6, "Stays?");
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(
+            report.summary,
+            SyncSummary {
+                created: 0,
+                updated: 2,
+                moved: 1,
+                conflicts: 0,
+                failed: 0
+            }
+        );
+        assert_eq!(sent(&anki, "changeDeck").len(), 1);
+        // The modification times are read once the notes were moved.
+        let actions: Vec<String> = anki.requests().into_iter().map(|r| r.action).collect();
+        assert_eq!(
+            actions[actions.len() - 2..],
+            ["changeDeck".to_string(), "notesInfo".to_string()]
+        );
+        assert_eq!(anki.note_decks(5), vec!["Rust"]);
+        assert_eq!(anki.note_decks(6), vec!["Default"]);
+        assert_eq!(
+            report.document.raw(),
+            indoc! {"
+                <!-- anki_id: 5, anki_deck: Rust, anki_hash: 67429f28bdb7e087, anki_mod: 1700000003 -->
+                ## Q: Moved?
+                Yes.
+
+                <!-- anki_id: 6, anki_hash: 3976946af409b161, anki_mod: 1700000004 -->
+                ## Q: Stays?
+                Yes.
+            "}
+        );
+    }
+
+    #[test]
+    fn test_tags_reconciled() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              tags: [keep, new]
+              tag_prefix: notes
+            ---
+            <!-- anki_id: 5 -->
+            ## Q: Tagged?
+            Yes.
+        "};
+        let anki = anki();
+        anki.insert_note(Note {
+            id: Some(5),
+            deck_name: "Default".to_string(),
+            model_name: "Basic".to_string(),
+            fields: NoteFields::from([("Front".to_string(), "Tagged?".to_string())]),
+            tags: vec![
+                "notes::old".to_string(),
+                "notes::keep".to_string(),
+                "by-hand".to_string(),
+            ],
+            options: None,
+        });
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(report.summary.updated, 1);
+        assert_eq!(
+            anki.note(5).unwrap().tags,
+            vec!["notes::keep", "by-hand", "notes::new"]
+        );
+        assert!(matches!(
+            &batched(&anki, "addTags")[..],
+            [Params::Tags { notes, tags }] if notes == &[5] && tags == "notes::new"
+        ));
+        assert_eq!(batched(&anki, "removeTags").len(), 1);
+        assert!(sent(&anki, "addTags").is_empty());
+
+        // Without a prefix no tag is known to come from the file, so none is removed.
+        let input = "<!-- anki_id: 5, anki_tags: [new] -->\n## Q: Tagged?\nNo.\n";
+        plan(input).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(
+            anki.note(5).unwrap().tags,
+            vec!["notes::keep", "by-hand", "notes::new", "new"]
+        );
+    }
+
+    #[test]
+    fn test_tags_compared_ignoring_case() {
+        let input = indoc! {"
+            <!-- anki_id: 5, anki_tags: [notes::rust] -->
+            ## Q: Tagged?
+            Yes.
+        "};
+        let anki = anki();
+        anki.insert_note(Note {
+            id: Some(5),
+            deck_name: "Default".to_string(),
+            model_name: "Basic".to_string(),
+            fields: NoteFields::from([("Front".to_string(), "Tagged?".to_string())]),
+            tags: vec!["notes::Rust".to_string()],
+            options: None,
+        });
+        let mut plan = plan(input);
+        plan.settings.tag_prefix = Some("notes".to_string());
+        let report = plan.sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(report.summary.updated, 1);
+        assert!(batched(&anki, "addTags").is_empty());
+        assert!(batched(&anki, "removeTags").is_empty());
+        assert_eq!(anki.note(5).unwrap().tags, vec!["notes::Rust"]);
+    }
+
+    #[test]
+    fn test_failed_tag_change_fails_block() {
+        let input = "<!-- anki_id: 5, anki_tags: [new] -->\n## Q: Tagged?\nYes.\n";
+        let anki = anki();
+        existing_note(&anki, 5, "Tagged?");
+        fail_action(&anki, "addTags", "collection is not available");
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(
+            report.errors[0].message,
+            "Cannot add tags to the note: Anki error: collection is not available"
+        );
+        assert_eq!(report.document.raw(), input);
+    }
+
+    /// Syncs a changed card whose note 5 was deleted in Anki, next to a new card.
+    fn sync_deleted(on_deleted: OnDeleted, anki: &FakeAnki) -> SyncReport {
+        let input = indoc! {"
+            <!-- anki_id: 5 -->
+            ## Q: Deleted?
+            Yes.
+
+            <!-- anki_deck: Rust -->
+            ## Q: New?
+            Yes.
+        "};
+        let mut plan = plan(input);
+        plan.options.on_deleted = on_deleted;
+        plan.sync(anki, &AtomicBool::new(false))
+    }
+
+    #[test]
+    fn test_deleted_note_recreated() {
+        let anki = anki();
+        let report = sync_deleted(OnDeleted::Recreate, &anki);
+        assert_eq!(report.summary.created, 2);
+        assert!(report.errors.is_empty());
+        assert!(
+            report
+                .document
+                .raw()
+                .starts_with("<!-- anki_id: 1, anki_hash: ")
+        );
+        assert_eq!(anki.note(1).unwrap().fields["Front"], "Deleted?");
+    }
+
+    #[test]
+    fn test_deleted_note_unsynced() {
+        let anki = anki();
+        let report = sync_deleted(OnDeleted::Unsync, &anki);
+        assert_eq!(report.summary.created, 1);
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            report.warnings,
+            vec![BlockSyncError {
+                line: 1,
+                front: "Deleted?".to_string(),
+                message: "Note 5 was deleted in Anki, the card is no longer synced".to_string(),
+            }]
+        );
+        assert!(
+            report
+                .document
+                .raw()
+                .starts_with("<!-- anki_id: 5, anki_sync: false -->\n")
+        );
+    }
+
+    #[test]
+    fn test_deleted_note_stops_sync() {
+        let anki = anki();
+        let report = sync_deleted(OnDeleted::Stop, &anki);
+        assert!(report.stopped);
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(report.summary.created, 0);
+        assert_eq!(report.errors[0].message, "Note 5 was deleted in Anki");
+        assert_eq!(sent(&anki, "multi").len(), 0);
+        assert!(sent(&anki, "createDeck").is_empty());
+        assert!(!anki.deck_names().unwrap().contains(&"Rust".to_string()));
+    }
+
+    #[test]
+    fn test_duplicate_note_adopted() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              tags: [rust]
+            ---
+            ## Q: First?
+            Yes.
+        "};
+        let anki = anki();
+        existing_note(&anki, 5, "First?");
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(report.summary.created, 0);
+        assert_eq!(report.summary.updated, 1);
+        assert!(report.errors.is_empty());
+        let note = anki.note(5).unwrap();
+        assert_eq!(note.fields["Back"], "<p>Yes.</p>\n");
+        assert_eq!(note.tags, vec!["rust"]);
+        assert!(
+            report
+                .document
+                .raw()
+                .contains("<!-- anki_id: 5, anki_hash: ")
+        );
+    }
+
+    #[test]
+    fn test_duplicates_of_a_deck_looked_up_once() {
+        let input = indoc! {"
+            ## Q: First?
+            Yes.
+
+            ## Q: Second?
+            Yes.
+        "};
+        let anki = anki();
+        existing_note(&anki, 5, "First?");
+        existing_note(&anki, 6, "Second?");
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(report.summary.updated, 2);
+        assert_eq!(sent(&anki, "findNotes").len(), 1);
+        assert!(report.document.raw().contains("<!-- anki_id: 6, "));
+    }
+
+    #[test]
+    fn test_duplicate_in_other_deck() {
+        let input = indoc! {"
+            <!-- anki_deck: Rust -->
+            ## Q: First?
+            Yes.
+        "};
+        let anki = anki();
+        existing_note(&anki, 5, "First?");
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(
+            report.errors[0].message,
+            "Anki has a note with the same Front outside of deck 'Rust'; \
+             set allow_duplicates or anki_allow_duplicate to create it anyway"
+        );
+
+        let scoped = input.replace("Rust", "Rust, anki_duplicate_scope: deck");
+        let report = plan(&scoped).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.created, 1);
+        let allowed = input.replace("Rust", "Rust, anki_allow_duplicate: true");
+        let report = plan(&allowed).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.created, 1);
+    }
+
+    #[test]
+    fn test_duplicate_in_subdeck_not_adopted() {
+        let input = indoc! {"
+            <!-- anki_deck: Rust -->
+            ## Q: First?
+            Yes.
+        "};
+        let anki = anki();
+        existing_note(&anki, 5, "First?");
+        anki.change_deck(&[50], "Rust::Traits").unwrap();
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(
+            report.errors[0].message,
+            "Anki has a note with the same Front outside of deck 'Rust'; \
+             set allow_duplicates or anki_allow_duplicate to create it anyway"
+        );
+        assert!(!anki.note(5).unwrap().fields.contains_key("Back"));
+    }
+
+    #[test]
+    fn test_duplicate_with_other_html_not_adopted() {
+        let input = indoc! {"
+            ## Q: First?
+            Yes.
+        "};
+        let anki = anki();
+        existing_note(&anki, 5, "<b>First?</b>");
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(
+            report.errors[0].message,
+            "Anki has a note in deck 'Default' whose Front only differs in its HTML: \
+             \"<b>First?</b>\"; make them the same or set allow_duplicates or \
+             anki_allow_duplicate to create it anyway"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_not_adopted_after_interrupt() {
+        let input = indoc! {"
+            ## Q: First?
+            Yes.
+        "};
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let anki = anki();
+        existing_note(&anki, 5, "First?");
+        let flag = interrupted.clone();
+        anki.fail_when(move |request| {
+            if request.action == "addNote" {
+                flag.store(true, Ordering::SeqCst);
+            }
+            None
+        });
+        let report = plan(input).sync(&anki, &interrupted);
+
+        assert!(report.interrupted);
+        assert!(report.errors.is_empty());
+        assert_eq!(report.document.raw(), input);
+        assert!(sent(&anki, "findNotes").is_empty());
+        assert!(!anki.note(5).unwrap().fields.contains_key("Back"));
+    }
+
+    #[test]
+    fn test_failed_move_keeps_update() {
+        let input = indoc! {"
+            <!-- anki_id: 5, anki_deck: Rust -->
+            ## Q: Moved?
+            Yes.
+        "};
+        let anki = anki();
+        existing_note(&anki, 5, "Moved?");
+        fail_action(&anki, "changeDeck", "deck was not found");
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(report.summary.updated, 1);
+        assert_eq!(report.summary.moved, 0);
+        assert_eq!(report.summary.failed, 0);
+        assert_eq!(
+            report.errors[0].message,
+            "Cannot move the note to deck 'Rust': Anki error: deck was not found"
+        );
+        assert_eq!(anki.note(5).unwrap().fields["Back"], "<p>Yes.</p>\n");
+        assert!(
+            report
+                .document
+                .raw()
+                .starts_with("<!-- anki_id: 5, anki_deck: Rust, anki_hash: ")
+        );
+    }
+
+    #[test]
+    fn test_missing_decks_created() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              deck: Lang::Rust
+            ---
+            ## Q: First?
+            Yes.
+
+            <!-- anki_deck: Default -->
+            ## Q: Second?
+            No.
+
+            <!-- anki_deck: Broken -->
+            ## Q: Third?
+            Maybe.
+        "};
+        let anki = anki();
+        anki.fail_when(|request| match &request.params {
+            Params::Deck { deck } if deck == "Broken" => {
+                Some(AnkiError::Anki("cannot create deck".to_string()))
+            }
+            _ => None,
+        });
+        let report = plan(input).sync(&anki, &AtomicBool::new(false));
+
+        assert_eq!(sent(&anki, "createDeck").len(), 2);
+        assert!(anki.decks().contains("Lang::Rust"));
+        assert_eq!(report.summary.created, 2);
+        assert_eq!(
+            report.errors,
+            vec![BlockSyncError {
+                line: 12,
+                front: "Third?".to_string(),
+                message: "Cannot create deck 'Broken': Anki error: cannot create deck".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_deck_creation_disabled() {
+        let (_, doc) = parse_document("## Q: First?\nYes.\n").unwrap();
+        let options = SyncOptions {
+            create_decks: false,
+            ..SyncOptions::default()
+        };
+        let plan = DocumentSyncPlan::from_document(doc, Path::new(""), Path::new(""), &options);
+        let anki = anki();
+        let report = plan.sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.created, 1);
+        assert!(sent(&anki, "deckNames").is_empty());
+        assert!(sent(&anki, "createDeck").is_empty());
+    }
+
+    #[test]
+    fn test_notes_sent_in_batches() {
+        let input = indoc! {"
+            ## Q: First?
+            Yes.
+
+            <!-- anki_sync: false -->
+            ## Q: Skipped?
+            Yes.
+
+            ## Q: Second?
+            No.
+
+            ## Q: Third?
+            Maybe.
+        "};
+        let anki = anki();
+        anki.fail_when(|request| match &request.params {
+            Params::Note { note } if note.fields["Front"] == "Second?" => {
+                Some(AnkiError::Anki("cannot create note".to_string()))
+            }
+            _ => None,
+        });
+        let mut plan = plan(input);
+        plan.options.batch_size = 2;
+        let report = plan.sync(&anki, &AtomicBool::new(false));
+
+        let batches: Vec<usize> = sent(&anki, "multi")
+            .into_iter()
+            .map(|params| match params {
+                Params::Multi { actions } => actions.len(),
+                _ => 0,
+            })
+            .collect();
+        assert_eq!(batches, vec![2, 1]);
+        assert_eq!(report.summary.created, 2);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].front, "Second?");
+    }
+
+    /// Syncs `input` to `anki` and returns the text written back.
+    fn synced(input: &str, anki: &FakeAnki) -> String {
+        plan(input)
+            .sync(anki, &AtomicBool::new(false))
+            .document
+            .raw()
+    }
+
+    #[test]
+    fn test_unchanged_note_not_sent_again() {
+        let anki = anki();
+        let first = synced("## Q: First?\nYes.\n", &anki);
+        let report = plan(&first).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary, SyncSummary::default());
+        assert_eq!(sent(&anki, "multi").len(), 1);
+        assert_eq!(report.document.raw(), first);
+    }
+
+    #[test]
+    fn test_note_changed_on_both_sides_is_conflict() {
+        let anki = anki();
+        let first = synced("## Q: First?\nYes.\n", &anki);
+        anki.edit_note(1, "Back", "<p>Yes!</p>");
+        let edited = first.replace("Yes.", "Yes, really.");
+
+        let report = plan(&edited).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.conflicts, 1);
+        assert_eq!(report.summary.failed, 0);
+        assert_eq!(report.errors[0].message, CONFLICT_MESSAGE);
+        assert_eq!(report.document.raw(), edited);
+        assert_eq!(anki.note(1).unwrap().fields["Back"], "<p>Yes!</p>");
+    }
+
+    #[test]
+    fn test_conflict_decided_by_modification_time() {
+        let anki = anki();
+        let first = synced("## Q: First?\nYes.\n", &anki);
+        let edited = first.replace("Yes.", "Yes, really.");
+
+        // Not changed in Anki since the last sync.
+        let report = plan(&edited).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.updated, 1);
+
+        // Changed in Anki, even to what the Markdown now holds.
+        let synced = report.document.raw();
+        anki.edit_note(1, "Back", "<p>Yes, truly.</p>\n");
+        let edited = synced.replace("really", "truly");
+        let report = plan(&edited).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.conflicts, 1);
+    }
+
+    #[test]
+    fn test_conflict_resolved_by_preference() {
+        let anki = anki();
+        let first = synced("## Q: First?\nYes.\n", &anki);
+        anki.edit_note(1, "Back", "<p>Yes!</p>");
+        let edited = first.replace("Yes.", "Yes, really.");
+
+        let mut markdown = plan(&edited);
+        markdown.options.prefer = Some(Prefer::Markdown);
+        let report = markdown.sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.updated, 1);
+        assert_eq!(
+            anki.note(1).unwrap().fields["Back"],
+            "<p>Yes, really.</p>\n"
+        );
+
+        anki.edit_note(1, "Back", "<p>Yes!</p>");
+        let edited = report.document.raw().replace("really", "truly");
+        let mut asked = Vec::new();
+        let report = plan(&edited).sync_resolving(&anki, &AtomicBool::new(false), |conflict| {
+            asked.push(conflict.note_id);
+            Some(Prefer::Anki)
+        });
+        assert_eq!(asked, vec![1]);
+        assert_eq!(report.summary, SyncSummary::default());
+        let pulled = report.document.raw();
+        assert!(pulled.ends_with("## Q: First?\nYes!\n"));
+
+        // Both sides now agree, so the next run has nothing to do.
+        let report = plan(&pulled).sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary, SyncSummary::default());
+        assert_eq!(report.document.raw(), pulled);
+    }
+
+    #[test]
+    fn test_cloze_changed_in_anki_not_pulled() {
+        let anki = anki();
+        let first = synced("## C: The capital of France is ==Paris==.\n", &anki);
+        anki.edit_note(1, "Text", "The capital of France is {{c1::Lyon}}.");
+        let edited = first.replace("France", "FRANCE");
+
+        let mut plan = plan(&edited);
+        plan.options.prefer = Some(Prefer::Anki);
+        let report = plan.sync(&anki, &AtomicBool::new(false));
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(
+            report.errors[0].message,
+            "Cannot pull this card type; edit it in the Markdown"
+        );
+        assert_eq!(report.document.raw(), edited);
+    }
+
+    #[test]
+    fn test_images_uploaded_once() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("img")).unwrap();
+        std::fs::write(dir.path().join("img/foo.png"), b"png").unwrap();
+        let input = indoc! {"
+            ## Q: What does it look like?
+            ![diagram](img/foo.png)
+
+            ## Q: Again?
+            ![diagram](img/foo.png)
+
+            ## Q: Missing?
+            Text first.
+            ![gone](img/gone.png)
+        "};
+        let (_, doc) = parse_document(input).unwrap();
+        let plan = DocumentSyncPlan::from_document(
+            doc,
+            &dir.path().join("notes.md"),
+            dir.path(),
+            &SyncOptions::default(),
+        );
+        let anki = anki();
+        let report = plan.sync(&anki, &AtomicBool::new(false));
+
+        let name = "8f8cbb7dcf46e0bc7d53265749a6c17d116093a6ba95e442764060c76fd4a86c.png";
+        assert_eq!(sent(&anki, "storeMediaFile").len(), 1);
+        assert_eq!(anki.media(name).as_deref(), Some("cG5n"));
+        assert_eq!(
+            anki.note(1).unwrap().fields["Back"],
+            format!("<p><img src=\"{}\" alt=\"diagram\" /></p>\n", name)
+        );
+        assert_eq!(report.summary.created, 2);
+        assert_eq!(
+            report.errors,
+            vec![BlockSyncError {
+                line: 7,
+                front: "Missing?".to_string(),
+                message: "Image 'img/gone.png' on line 9 was not found".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_math_delimiters_converted() {
+        let plan = plan(indoc! {"
+            ## Q: What is $x_1^2$ for $x_1 = 3$?
+            $$
+            x_1^2 = 9
+            $$
+            Costs `$5$`, or $5 to $10.
+        "});
+        let note = planned_note(&plan, 0);
+        assert_eq!(
+            note.fields["Front"],
+            "What is \\(x_1^2\\) for \\(x_1 = 3\\)?"
+        );
+        assert_eq!(
+            note.fields["Back"],
+            "<p>\\[\nx_1^2 = 9\n\\]\nCosts <code>$5$</code>, or $5 to $10.</p>\n"
+        );
+    }
+}
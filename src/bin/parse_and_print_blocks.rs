@@ -31,9 +31,10 @@ fn main() {
     if let Some(ref fm) = doc.front_matter {
         match fm {
             FrontMatter::Raw { .. } => println!("FrontMatter: Raw"),
-            FrontMatter::AnkiSync { deck, tags, .. } => {
-                println!("FrontMatter: AnkiSync (deck: {:?}, tags: {:?})", deck, tags);
+            FrontMatter::AnkiSync { settings, .. } => {
+                println!("FrontMatter: AnkiSync ({:?})", settings);
             }
+            FrontMatter::Invalid { error, .. } => println!("FrontMatter: Invalid ({})", error),
         }
     }
 
@@ -48,8 +49,29 @@ fn main() {
                 ..
             } => {
                 println!(
-                    "FlashCardWithMeta: id={:?}, sync={:?}, deck={:?}, tags={:?} | {}",
-                    metadata.id, metadata.sync, metadata.deck, metadata.tags, flashcard.front
+                    "FlashCardWithMeta: id={:?}, sync={:?}, deck={:?}, model={:?}, tags={:?} | {}",
+                    metadata.id,
+                    metadata.sync,
+                    metadata.deck,
+                    metadata.model,
+                    metadata.tags,
+                    flashcard.front
+                );
+            }
+            Block::Cloze(cloze) => {
+                println!("Cloze: {}", cloze.text);
+            }
+            Block::ClozeWithMeta {
+                metadata, cloze, ..
+            } => {
+                println!(
+                    "ClozeWithMeta: id={:?}, sync={:?}, deck={:?}, model={:?}, tags={:?} | {}",
+                    metadata.id,
+                    metadata.sync,
+                    metadata.deck,
+                    metadata.model,
+                    metadata.tags,
+                    cloze.text
                 );
             }
             Block::Passthrough(_) => {
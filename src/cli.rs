@@ -0,0 +1,180 @@
+use crate::anki_sync::{OnDeleted, Prefer, SyncOptions};
+use crate::orphans::OrphanPolicy;
+use crate::render::RenderMode;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: markdown-anki-sync [--pull] [--dry-run] [--no-render] [--no-create-decks] [--batch-size <n>]\n       [--prefer markdown|anki|ask] [--on-deleted recreate|unsync|stop]\n       [--orphans report|suspend|tag|delete] [--root <directory>] <file|directory|glob>...";
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub inputs: Vec<String>,
+    pub dry_run: bool,
+    /// Update the Markdown files from Anki instead of Anki from the files.
+    pub pull: bool,
+    /// Ask which side to keep for every note that changed in both Anki and the Markdown.
+    pub ask_on_conflict: bool,
+    /// What happens to notes whose cards were removed from their file.
+    pub orphans: OrphanPolicy,
+    /// Directory `deck_from_path` takes every path relative to, instead of the input the
+    /// file was found through.
+    pub root: Option<PathBuf>,
+    pub sync: SyncOptions,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--pull" => options.pull = true,
+            "--no-render" => options.sync.render = RenderMode::Raw,
+            "--no-create-decks" => options.sync.create_decks = false,
+            "--batch-size" => {
+                options.sync.batch_size = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or("--batch-size expects a positive number")?;
+            }
+            "--prefer" => match args.next().map(String::as_str) {
+                Some("markdown") => options.sync.prefer = Some(Prefer::Markdown),
+                Some("anki") => options.sync.prefer = Some(Prefer::Anki),
+                Some("ask") => options.ask_on_conflict = true,
+                _ => return Err("--prefer expects markdown, anki or ask".to_string()),
+            },
+            "--on-deleted" => match args.next().map(String::as_str) {
+                Some("recreate") => options.sync.on_deleted = OnDeleted::Recreate,
+                Some("unsync") => options.sync.on_deleted = OnDeleted::Unsync,
+                Some("stop") => options.sync.on_deleted = OnDeleted::Stop,
+                _ => return Err("--on-deleted expects recreate, unsync or stop".to_string()),
+            },
+            "--orphans" => match args.next().map(String::as_str) {
+                Some("report") => options.orphans = OrphanPolicy::Report,
+                Some("suspend") => options.orphans = OrphanPolicy::Suspend,
+                Some("tag") => options.orphans = OrphanPolicy::Tag,
+                Some("delete") => options.orphans = OrphanPolicy::Delete,
+                _ => return Err("--orphans expects report, suspend, tag or delete".to_string()),
+            },
+            "--root" => {
+                options.root = Some(args.next().ok_or("--root expects a directory")?.into());
+            }
+            "--" => options.inputs.extend(args.by_ref().cloned()),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'", flag)),
+            input => options.inputs.push(input.to_string()),
+        }
+    }
+
+    if options.inputs.is_empty() {
+        return Err("No input files given".to_string());
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_inputs_only() {
+        let options = parse_args(&args(&["notes.md", "vault"])).unwrap();
+        assert_eq!(options.inputs, vec!["notes.md", "vault"]);
+        assert!(!options.dry_run);
+        assert!(!options.pull);
+        assert_eq!(options.sync.render, RenderMode::Html);
+        assert!(options.sync.create_decks);
+    }
+
+    #[test]
+    fn test_no_render() {
+        let options = parse_args(&args(&["--no-render", "notes.md"])).unwrap();
+        assert_eq!(options.sync.render, RenderMode::Raw);
+    }
+
+    #[test]
+    fn test_no_create_decks() {
+        let options = parse_args(&args(&["--no-create-decks", "notes.md"])).unwrap();
+        assert!(!options.sync.create_decks);
+    }
+
+    #[test]
+    fn test_batch_size() {
+        let options = parse_args(&args(&["--batch-size", "25", "notes.md"])).unwrap();
+        assert_eq!(options.sync.batch_size, 25);
+        assert!(parse_args(&args(&["--batch-size", "0", "notes.md"])).is_err());
+        assert!(parse_args(&args(&["notes.md", "--batch-size"])).is_err());
+    }
+
+    #[test]
+    fn test_prefer() {
+        let options = parse_args(&args(&["--prefer", "anki", "notes.md"])).unwrap();
+        assert_eq!(options.sync.prefer, Some(Prefer::Anki));
+        assert!(!options.ask_on_conflict);
+        let options = parse_args(&args(&["--prefer", "ask", "notes.md"])).unwrap();
+        assert_eq!(options.sync.prefer, None);
+        assert!(options.ask_on_conflict);
+        assert!(parse_args(&args(&["--prefer", "both", "notes.md"])).is_err());
+    }
+
+    #[test]
+    fn test_on_deleted() {
+        let options = parse_args(&args(&["notes.md"])).unwrap();
+        assert_eq!(options.sync.on_deleted, OnDeleted::Stop);
+        let options = parse_args(&args(&["--on-deleted", "recreate", "notes.md"])).unwrap();
+        assert_eq!(options.sync.on_deleted, OnDeleted::Recreate);
+        assert!(parse_args(&args(&["--on-deleted", "skip", "notes.md"])).is_err());
+    }
+
+    #[test]
+    fn test_orphans() {
+        let options = parse_args(&args(&["notes.md"])).unwrap();
+        assert_eq!(options.orphans, OrphanPolicy::Report);
+        let options = parse_args(&args(&["--orphans", "suspend", "notes.md"])).unwrap();
+        assert_eq!(options.orphans, OrphanPolicy::Suspend);
+        assert!(parse_args(&args(&["--orphans", "keep", "notes.md"])).is_err());
+    }
+
+    #[test]
+    fn test_root() {
+        let options = parse_args(&args(&["--root", "vault", "vault/rust"])).unwrap();
+        assert_eq!(options.root, Some(PathBuf::from("vault")));
+        assert_eq!(options.inputs, vec!["vault/rust"]);
+        assert!(parse_args(&args(&["notes.md", "--root"])).is_err());
+    }
+
+    #[test]
+    fn test_pull() {
+        let options = parse_args(&args(&["--pull", "--dry-run", "notes.md"])).unwrap();
+        assert!(options.pull);
+        assert!(options.dry_run);
+    }
+
+    #[test]
+    fn test_dry_run_anywhere() {
+        let options = parse_args(&args(&["notes.md", "--dry-run"])).unwrap();
+        assert_eq!(options.inputs, vec!["notes.md"]);
+        assert!(options.dry_run);
+    }
+
+    #[test]
+    fn test_double_dash_ends_options() {
+        let options = parse_args(&args(&["--", "--dry-run"])).unwrap();
+        assert_eq!(options.inputs, vec!["--dry-run"]);
+        assert!(!options.dry_run);
+    }
+
+    #[test]
+    fn test_unknown_option_rejected() {
+        assert!(parse_args(&args(&["--force", "notes.md"])).is_err());
+    }
+
+    #[test]
+    fn test_no_inputs_rejected() {
+        assert!(parse_args(&args(&["--dry-run"])).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::anki::Note;
+use crate::anki_sync::{Action, BlockSyncPlan, DocumentSyncPlan};
+
+/// Placeholder shown in place of the IDs Anki would assign to created notes.
+const NEW_ID: &str = "<new>";
+
+fn describe_note(note: &Note, front: &str) -> String {
+    format!(
+        "deck: {:?}, model: {:?}, tags: {:?}, front: {:?}",
+        note.deck_name, note.model_name, note.tags, front
+    )
+}
+
+fn describe_block_plan(block_plan: &BlockSyncPlan) -> String {
+    let front = block_plan.front().unwrap_or_default();
+    match &block_plan.action {
+        Action::CreateNote(note) => format!("CreateNote {}", describe_note(note, front)),
+        Action::UpdateNote(note) if !block_plan.markdown_changed() => format!(
+            "Unchanged id: {}, {}",
+            note.id.map_or_else(|| "?".to_string(), |id| id.to_string()),
+            describe_note(note, front)
+        ),
+        Action::UpdateNote(note) => format!(
+            "UpdateNote id: {}, {}",
+            note.id.map_or_else(|| "?".to_string(), |id| id.to_string()),
+            describe_note(note, front)
+        ),
+        Action::DoNothing => match block_plan.front() {
+            Some(front) => format!("DoNothing front: {:?}", front),
+            None => "DoNothing (passthrough)".to_string(),
+        },
+    }
+}
+
+/// Lists every block plan with its action, target deck, tags and front text.
+pub fn describe_plan(plan: &DocumentSyncPlan) -> String {
+    plan.block_plans
+        .iter()
+        .map(|block_plan| format!("{}\n", describe_block_plan(block_plan)))
+        .collect()
+}
+
+fn raw_lines(raw: &str) -> Vec<&str> {
+    raw.lines().collect()
+}
+
+fn hunk_range(start: usize, len: usize) -> String {
+    if len == 1 {
+        start.to_string()
+    } else {
+        format!("{},{}", start, len)
+    }
+}
+
+/// Raw text of the block as the sync would write it back, with the ID of a created
+/// note replaced by a placeholder.
+fn planned_raw(block_plan: &BlockSyncPlan) -> Option<String> {
+    let block = match &block_plan.action {
+        Action::CreateNote(_) => block_plan.created_block_preview(NEW_ID),
+        Action::UpdateNote(Note { id: Some(id), .. }) if block_plan.markdown_changed() => {
+            let modified = block_plan.block.metadata().and_then(|m| m.modified);
+            block_plan.synced_block(*id, modified)
+        }
+        _ => return None,
+    };
+    block.ok().map(|block| block.raw())
+}
+
+/// Unified diff of the metadata comments a sync would write back into the file.
+/// Returns an empty string when the file would be left unchanged.
+pub fn metadata_diff(path: &str, plan: &DocumentSyncPlan) -> String {
+    let mut hunks = String::new();
+    let mut old_line = 1;
+    let mut new_line = 1;
+
+    if let Some(front_matter) = &plan.front_matter {
+        old_line += front_matter.raw().lines().count();
+        new_line += front_matter.raw().lines().count();
+    }
+
+    for block_plan in &plan.block_plans {
+        let old_raw = block_plan.block.raw();
+        let old_lines = raw_lines(&old_raw);
+        let new_raw = planned_raw(block_plan).unwrap_or_else(|| old_raw.clone());
+        let new_lines = raw_lines(&new_raw);
+
+        if old_raw != new_raw {
+            // Metadata only ever changes in front of the card, so the common suffix is context.
+            let common_suffix = old_lines
+                .iter()
+                .rev()
+                .zip(new_lines.iter().rev())
+                .take_while(|(old, new)| old == new)
+                .count();
+            let removed = &old_lines[..old_lines.len() - common_suffix];
+            let added = &new_lines[..new_lines.len() - common_suffix];
+            let context = old_lines.get(removed.len());
+
+            let context_len = usize::from(context.is_some());
+            hunks.push_str(&format!(
+                "@@ -{} +{} @@\n",
+                hunk_range(old_line, removed.len() + context_len),
+                hunk_range(new_line, added.len() + context_len)
+            ));
+            removed
+                .iter()
+                .for_each(|line| hunks.push_str(&format!("-{}\n", line)));
+            added
+                .iter()
+                .for_each(|line| hunks.push_str(&format!("+{}\n", line)));
+            if let Some(line) = context {
+                hunks.push_str(&format!(" {}\n", line));
+            }
+        }
+
+        old_line += old_lines.len();
+        new_line += new_lines.len();
+    }
+
+    if hunks.is_empty() {
+        hunks
+    } else {
+        format!("--- {}\n+++ {}\n{}", path, path, hunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki_sync::SyncOptions;
+    use crate::parser::document::parse_document;
+    use indoc::indoc;
+    use std::path::Path;
+
+    fn plan(input: &str) -> DocumentSyncPlan {
+        let (_, doc) = parse_document(input).unwrap();
+        DocumentSyncPlan::from_document(doc, Path::new(""), Path::new(""), &SyncOptions::default())
+    }
+
+    #[test]
+    fn test_describe_plan() {
+        let plan = plan(indoc! {"
+            ---
+            anki_sync:
+              deck: Rust
+              tags: [lang]
+            ---
+            # Intro
+
+            <!-- anki_id: 7 -->
+            ## Q: What is Rust?
+            A language.
+
+            ## Q: What is Nom?
+            A parser library.
+
+            <!-- anki_tags_add: [hard] -->
+            ## Q: What is a lifetime?
+            A region of code.
+
+            <!-- anki_id: 9, anki_sync: false -->
+            ## Q: Skipped?
+            Yes.
+        "});
+        assert_eq!(
+            describe_plan(&plan),
+            indoc! {r#"
+                DoNothing (passthrough)
+                UpdateNote id: 7, deck: "Rust", model: "Basic", tags: ["lang"], front: "What is Rust?"
+                CreateNote deck: "Rust", model: "Basic", tags: ["lang"], front: "What is Nom?"
+                CreateNote deck: "Rust", model: "Basic", tags: ["lang", "hard"], front: "What is a lifetime?"
+                DoNothing front: "Skipped?"
+            "#}
+        );
+    }
+
+    #[test]
+    fn test_metadata_diff() {
+        let plan = plan(indoc! {"
+            # Intro
+
+            <!-- anki_sync: true, anki_id: 7 -->
+            ## Q: What is Rust?
+            A language.
+
+            <!-- anki_id: 8, anki_hash: 0f09be09af5b7bb0 -->
+            ## Q: Unchanged?
+            Yes.
+
+            ## Q: What is Nom?
+            A parser library.
+        "});
+        assert_eq!(
+            metadata_diff("notes.md", &plan),
+            indoc! {"
+                --- notes.md
+                +++ notes.md
+                @@ -3,2 +3,2 @@
+                -<!-- anki_sync: true, anki_id: 7 -->
+                +<!-- anki_sync: true, anki_id: 7, anki_hash: ce9fe0e7780669c5 -->
+                 ## Q: What is Rust?
+                @@ -11 +11,3 @@
+                +<!-- anki_id: <new>, anki_hash: e07086b4a724d857 -->
+                +
+                 ## Q: What is Nom?
+            "}
+        );
+    }
+
+    #[test]
+    fn test_metadata_diff_empty_when_unchanged() {
+        let plan = plan(indoc! {"
+            <!-- anki_id: 7, anki_hash: ce9fe0e7780669c5, anki_mod: 1700000000 -->
+            ## Q: What is Rust?
+            A language.
+        "});
+        assert_eq!(metadata_diff("notes.md", &plan), "");
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+const MARKDOWN_EXTENSIONS: [&str; 2] = ["md", "markdown"];
+
+fn is_markdown_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            MARKDOWN_EXTENSIONS
+                .iter()
+                .any(|md| ext.eq_ignore_ascii_case(md))
+        })
+}
+
+/// Hidden entries such as `.git`, `.obsidian` or `.trash` are never walked into.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+fn is_glob_pattern(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+/// A Markdown file to sync and the directory its path is taken relative to for
+/// `deck_from_path`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarkdownFile {
+    pub path: PathBuf,
+    /// The directory given on the command line that the file was found in, the
+    /// directory of a file given by name, or the part of a glob pattern before its first
+    /// wildcard.
+    pub root: PathBuf,
+}
+
+type Files = BTreeMap<PathBuf, PathBuf>;
+
+fn walk_directory(dir: &Path, root: &Path, files: &mut Files) -> Result<(), String> {
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("Error reading '{}': {}", dir.display(), e))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("Error reading '{}': {}", dir.display(), e))?
+            .path();
+        if is_hidden(&path) {
+            continue;
+        }
+        if path.is_dir() {
+            walk_directory(&path, root, files)?;
+        } else if is_markdown_file(&path) {
+            files.entry(path).or_insert_with(|| root.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+fn collect_path(path: &Path, files: &mut Files) -> Result<(), String> {
+    if path.is_dir() {
+        walk_directory(path, path, files)
+    } else if path.is_file() {
+        let root = path.parent().unwrap_or(Path::new(""));
+        files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| root.to_path_buf());
+        Ok(())
+    } else {
+        Err(format!("No such file or directory: '{}'", path.display()))
+    }
+}
+
+/// The directories of `pattern` in front of its first wildcard.
+fn glob_root(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| match component {
+            Component::Normal(name) => !is_glob_pattern(&name.to_string_lossy()),
+            _ => true,
+        })
+        .collect()
+}
+
+fn collect_glob(pattern: &str, files: &mut Files) -> Result<(), String> {
+    let root = glob_root(pattern);
+    // Like the directory walk, wildcards do not match hidden entries.
+    let options = glob::MatchOptions {
+        require_literal_leading_dot: true,
+        ..glob::MatchOptions::new()
+    };
+    let paths = glob::glob_with(pattern, options)
+        .map_err(|e| format!("Invalid glob pattern '{}': {}", pattern, e))?;
+    for path in paths {
+        let path = path.map_err(|e| format!("Error reading '{}': {}", pattern, e))?;
+        if path.is_dir() {
+            walk_directory(&path, &root, files)?;
+        } else if is_markdown_file(&path) {
+            files.entry(path).or_insert_with(|| root.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Expands files, directories and glob patterns into a sorted, de-duplicated list of
+/// Markdown files. Directories are walked recursively; files given explicitly are kept
+/// regardless of their extension. A file found through several inputs keeps the root of
+/// the first one.
+pub fn collect_markdown_files(inputs: &[String]) -> Result<Vec<MarkdownFile>, String> {
+    let mut files = Files::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if !path.exists() && is_glob_pattern(input) {
+            collect_glob(input, &mut files)?;
+        } else {
+            collect_path(path, &mut files)?;
+        }
+    }
+    Ok(files
+        .into_iter()
+        .map(|(path, root)| MarkdownFile { path, root })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn vault() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("rust/ownership")).unwrap();
+        fs::create_dir_all(root.join(".obsidian")).unwrap();
+        fs::write(root.join("index.md"), "").unwrap();
+        fs::write(root.join("rust/basics.md"), "").unwrap();
+        fs::write(root.join("rust/ownership/borrowing.markdown"), "").unwrap();
+        fs::write(root.join("rust/notes.txt"), "").unwrap();
+        fs::write(root.join(".obsidian/workspace.md"), "").unwrap();
+        dir
+    }
+
+    fn relative(root: &Path, files: Vec<MarkdownFile>) -> Vec<String> {
+        files
+            .into_iter()
+            .map(|f| {
+                f.path
+                    .strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_directory_is_walked_recursively() {
+        let dir = vault();
+        let files = collect_markdown_files(&[dir.path().to_string_lossy().into_owned()]).unwrap();
+        assert_eq!(
+            relative(dir.path(), files),
+            vec![
+                "index.md",
+                "rust/basics.md",
+                "rust/ownership/borrowing.markdown"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explicit_file_kept_regardless_of_extension() {
+        let dir = vault();
+        let file = dir.path().join("rust/notes.txt");
+        let files = collect_markdown_files(&[file.to_string_lossy().into_owned()]).unwrap();
+        assert_eq!(
+            files,
+            vec![MarkdownFile {
+                path: file,
+                root: dir.path().join("rust")
+            }]
+        );
+    }
+
+    #[test]
+    fn test_glob_pattern() {
+        let dir = vault();
+        let pattern = dir.path().join("rust/*");
+        let files = collect_markdown_files(&[pattern.to_string_lossy().into_owned()]).unwrap();
+        assert_eq!(
+            relative(dir.path(), files),
+            vec!["rust/basics.md", "rust/ownership/borrowing.markdown"]
+        );
+    }
+
+    #[test]
+    fn test_glob_skips_hidden_entries() {
+        let dir = vault();
+        let pattern = dir.path().join("**/*.md");
+        let files = collect_markdown_files(&[pattern.to_string_lossy().into_owned()]).unwrap();
+        assert_eq!(
+            relative(dir.path(), files),
+            vec!["index.md", "rust/basics.md"]
+        );
+    }
+
+    #[test]
+    fn test_overlapping_inputs_deduplicated() {
+        let dir = vault();
+        let inputs = [
+            dir.path().join("rust").to_string_lossy().into_owned(),
+            dir.path()
+                .join("rust/basics.md")
+                .to_string_lossy()
+                .into_owned(),
+        ];
+        let files = collect_markdown_files(&inputs).unwrap();
+        assert_eq!(
+            relative(dir.path(), files),
+            vec!["rust/basics.md", "rust/ownership/borrowing.markdown"]
+        );
+    }
+
+    #[test]
+    fn test_roots_of_inputs() {
+        let dir = vault();
+        let inputs = [
+            dir.path().join("rust").to_string_lossy().into_owned(),
+            dir.path().join("*.md").to_string_lossy().into_owned(),
+        ];
+        let roots: Vec<PathBuf> = collect_markdown_files(&inputs)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.root)
+            .collect();
+        assert_eq!(
+            roots,
+            vec![
+                dir.path().to_path_buf(),
+                dir.path().join("rust"),
+                dir.path().join("rust")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_path_fails() {
+        let dir = vault();
+        let missing = dir.path().join("missing.md");
+        assert!(collect_markdown_files(&[missing.to_string_lossy().into_owned()]).is_err());
+    }
+}
@@ -1,5 +1,13 @@
 pub mod anki;
 pub mod anki_sync;
+pub mod cli;
+pub mod dry_run;
+pub mod files;
+pub mod media;
+pub mod orphans;
 pub mod parser;
+pub mod pull;
+pub mod render;
+pub mod tags;
 pub mod types;
 pub mod writer;
@@ -1,49 +1,316 @@
-use markdown_anki_sync::anki::Response;
-use markdown_anki_sync::anki_sync::DocumentSyncPlan;
+use markdown_anki_sync::anki::AnkiClient;
+use markdown_anki_sync::anki::http::HttpTransport;
+use markdown_anki_sync::anki_sync::{
+    Conflict, DocumentSyncPlan, Prefer, SyncSummary, relative_path,
+};
+use markdown_anki_sync::cli::{Options, USAGE, parse_args};
+use markdown_anki_sync::dry_run::{describe_plan, metadata_diff};
+use markdown_anki_sync::files::{MarkdownFile, collect_markdown_files};
+use markdown_anki_sync::orphans::{
+    OrphanPolicy, card_ids, find_orphans, handle_orphans, note_front,
+};
 use markdown_anki_sync::parser::document::parse_document;
+use markdown_anki_sync::pull::PullSummary;
+use markdown_anki_sync::tags::file_source_tag;
+use markdown_anki_sync::types::FrontMatter;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::env;
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+use std::ops::AddAssign;
+use std::path::Path;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <markdown-file>", args[0]);
-        process::exit(1);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Set when a file stops the run, so that no further files are synced.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// The directory the deck and source tag of `file` are taken relative to.
+fn sync_root<'a>(file: &'a MarkdownFile, options: &'a Options) -> &'a Path {
+    options.root.as_deref().unwrap_or(&file.root)
+}
+
+fn read_plan(file: &MarkdownFile, options: &Options) -> Result<(String, DocumentSyncPlan), String> {
+    let path = file.path.as_path();
+    let root = sync_root(file, options);
+    if relative_path(path, root).is_none() {
+        return Err(format!("Not below the root '{}'", root.display()));
+    }
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Error reading '{}': {}", path.display(), e))?;
+
+    let (rest, doc) = parse_document(&contents).map_err(|e| format!("Parse error: {}", e))?;
+
+    if !rest.is_empty() {
+        eprintln!(
+            "Warning: {}: unparsed remaining input ({} bytes)",
+            path.display(),
+            rest.len()
+        );
     }
 
-    let filename = &args[1];
-    let contents = match std::fs::read_to_string(filename) {
-        Ok(c) => c,
+    if let Some(FrontMatter::Invalid { error, .. }) = &doc.front_matter {
+        return Err(format!("Invalid front matter: {}", error));
+    }
+
+    let sync_plan = DocumentSyncPlan::from_document(doc, path, root, &options.sync);
+    Ok((contents, sync_plan))
+}
+
+fn write_back(path: &Path, contents: &str, new_contents: &str) -> Result<(), String> {
+    if new_contents != contents {
+        std::fs::write(path, new_contents)
+            .map_err(|e| format!("Error writing '{}': {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Asks on the terminal which side of a conflict to keep. Any answer other than
+/// Markdown or Anki leaves the note alone.
+fn ask_side(path: &Path, conflict: &Conflict) -> Option<Prefer> {
+    eprint!(
+        "{}:{} ({:?}) changed in both Anki and the Markdown. Keep [m]arkdown, [a]nki or [s]kip? ",
+        path.display(),
+        conflict.line,
+        conflict.front
+    );
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok()?;
+    match answer.trim() {
+        "m" | "markdown" => Some(Prefer::Markdown),
+        "a" | "anki" => Some(Prefer::Anki),
+        _ => None,
+    }
+}
+
+fn sync_file(
+    file: &MarkdownFile,
+    options: &Options,
+    client: &impl AnkiClient,
+    synced: &RefCell<SyncedFiles>,
+) -> Result<SyncSummary, String> {
+    let path = file.path.as_path();
+    let (contents, sync_plan) = read_plan(file, options)?;
+
+    if options.dry_run {
+        print!("{}", describe_plan(&sync_plan));
+        print!("{}", metadata_diff(&path.display().to_string(), &sync_plan));
+        return Ok(sync_plan.planned_summary());
+    }
+
+    let report = if options.ask_on_conflict {
+        sync_plan.sync_resolving(client, &INTERRUPTED, |conflict| ask_side(path, conflict))
+    } else {
+        sync_plan.sync(client, &INTERRUPTED)
+    };
+    for error in &report.errors {
+        eprintln!("{}:{}", path.display(), error);
+    }
+    for warning in &report.warnings {
+        eprintln!("Warning: {}:{}", path.display(), warning);
+    }
+    if report.stopped {
+        STOPPED.store(true, Ordering::SeqCst);
+        return Err(
+            "Stopped because a note was deleted in Anki; pass --on-deleted recreate or unsync to go on"
+                .to_string(),
+        );
+    }
+
+    // Written back even when some blocks failed, so the IDs of created notes are kept.
+    write_back(path, &contents, &report.document.raw())?;
+
+    if !report.interrupted {
+        let mut synced = synced.borrow_mut();
+        synced.files.push(MarkdownFile {
+            path: path.to_path_buf(),
+            root: sync_root(file, options).to_path_buf(),
+        });
+        synced.ids.extend(card_ids(&report.document));
+    }
+    Ok(report.summary)
+}
+
+/// The files a run synced to the end, with the root of their sync, and the IDs of all
+/// their cards.
+#[derive(Default)]
+struct SyncedFiles {
+    files: Vec<MarkdownFile>,
+    ids: BTreeSet<u64>,
+}
+
+/// Lists the orphaned notes of every synced file and applies the policy to them.
+/// Notes are only deleted when every file was synced, since a card whose file was
+/// skipped or failed may have moved there. Returns the number of files that failed.
+fn handle_run_orphans(
+    synced: &SyncedFiles,
+    complete: bool,
+    options: &Options,
+    client: &impl AnkiClient,
+) -> usize {
+    let mut policy = options.orphans;
+    if policy == OrphanPolicy::Delete && !complete {
+        eprintln!("Not deleting orphaned notes, since not every file was synced");
+        policy = OrphanPolicy::Report;
+    }
+    let mut failed_files = 0;
+    for MarkdownFile { path, root } in &synced.files {
+        let Some(tag) = file_source_tag(path, root) else {
+            continue;
+        };
+        let result = find_orphans(&tag, &synced.ids, client).and_then(|orphans| {
+            for info in &orphans {
+                println!(
+                    "{}: note {} ({:?}) is no longer in the file",
+                    path.display(),
+                    info.note_id,
+                    note_front(info)
+                );
+            }
+            handle_orphans(&orphans, policy, client)?;
+            if !orphans.is_empty() && policy != OrphanPolicy::Report {
+                println!(
+                    "{}: {} orphaned notes {}",
+                    path.display(),
+                    orphans.len(),
+                    policy.done()
+                );
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            eprintln!("{}: Failed: {}", path.display(), e);
+            failed_files += 1;
+        }
+    }
+    failed_files
+}
+
+fn pull_file(
+    file: &MarkdownFile,
+    options: &Options,
+    client: &impl AnkiClient,
+) -> Result<PullSummary, String> {
+    let path = file.path.as_path();
+    let (contents, sync_plan) = read_plan(file, options)?;
+    let report = sync_plan.pull(client)?;
+    for error in &report.errors {
+        eprintln!("{}:{}", path.display(), error);
+    }
+
+    if !options.dry_run {
+        write_back(path, &contents, &report.document.raw())?;
+    }
+    Ok(report.summary)
+}
+
+/// Runs `process` on every file until interrupted, printing the summary of each, and
+/// returns the total with the number of files that failed.
+fn process_files<S: Default + AddAssign + Display>(
+    files: &[MarkdownFile],
+    options: &Options,
+    process: impl Fn(&MarkdownFile) -> Result<S, String>,
+) -> (S, usize) {
+    let mut total = S::default();
+    let mut failed_files = 0;
+    for file in files {
+        if INTERRUPTED.load(Ordering::SeqCst) || STOPPED.load(Ordering::SeqCst) {
+            break;
+        }
+        let path = file.path.display();
+        if options.dry_run && !options.pull {
+            println!("== {}", path);
+        }
+        match process(file) {
+            Ok(summary) => {
+                println!("{}: {}", path, summary);
+                total += summary;
+            }
+            Err(e) => {
+                eprintln!("{}: Failed: {}", path, e);
+                failed_files += 1;
+            }
+        }
+    }
+    (total, failed_files)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
         Err(e) => {
-            eprintln!("Error reading '{}': {}", filename, e);
+            eprintln!("{}\n{}", e, USAGE);
             process::exit(1);
         }
     };
+    if options.ask_on_conflict && !options.dry_run && !io::stdin().is_terminal() {
+        eprintln!("--prefer ask needs a terminal to ask on; pass --prefer markdown or anki");
+        process::exit(1);
+    }
 
-    let (rest, doc) = match parse_document(&contents) {
-        Ok(result) => result,
+    let files = match collect_markdown_files(&options.inputs) {
+        Ok(files) => files,
         Err(e) => {
-            eprintln!("Parse error: {}", e);
+            eprintln!("{}", e);
             process::exit(1);
         }
     };
 
-    if !rest.is_empty() {
-        eprintln!("Warning: unparsed remaining input ({} bytes)", rest.len());
+    // Checked up front so that a missing Anki is not mistaken for every card failing.
+    let client = HttpTransport::default();
+    if (!options.dry_run || options.pull)
+        && let Err(e) = client.version()
+    {
+        eprintln!("{}", e);
+        process::exit(1);
     }
 
-    let sync_plan = DocumentSyncPlan::from_document(doc);
+    let handler = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+        eprintln!("Interrupted, writing back notes synced so far (press Ctrl-C again to abort)");
+    });
+    if let Err(e) = handler {
+        eprintln!("Warning: cannot handle Ctrl-C: {}", e);
+    }
 
-    let (synced_document, created_count, updated_count) =
-        sync_plan.sync(|request| {
-            ureq::post("http://localhost:8765")
-                .send_json(request)
-                .and_then(|mut body| body.body_mut().read_json::<Response>())
-                .ok()
-        })?;
+    let (total, failed_files, failed_blocks) = if options.pull {
+        let (total, failed_files) =
+            process_files(&files, &options, |file| pull_file(file, &options, &client));
+        (total.to_string(), failed_files, total.failed)
+    } else {
+        let synced = RefCell::new(SyncedFiles::default());
+        let (total, mut failed_files) = process_files(&files, &options, |file| {
+            sync_file(file, &options, &client, &synced)
+        });
+        if !options.dry_run && !INTERRUPTED.load(Ordering::SeqCst) {
+            let synced = synced.into_inner();
+            let complete = failed_files == 0 && synced.files.len() == files.len();
+            failed_files += handle_run_orphans(&synced, complete, &options, &client);
+        }
+        (
+            total.to_string(),
+            failed_files,
+            total.failed + total.conflicts,
+        )
+    };
 
-    println!("Created: {}, Updated: {}", created_count, updated_count);
+    println!(
+        "Total ({} files, {} failed): {}{}",
+        files.len(),
+        failed_files,
+        total,
+        if options.dry_run { " (dry run)" } else { "" }
+    );
 
-    std::fs::write(filename, synced_document.raw())?;
-    Ok(())
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        process::exit(130);
+    }
+    if failed_files > 0 || failed_blocks > 0 {
+        process::exit(1);
+    }
 }
@@ -0,0 +1,160 @@
+use crate::parser::code_fence::{code_span_end, map_outside_fences};
+use crate::parser::image::parse_image;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An image a card references by a relative path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaFile {
+    /// The path as written in the Markdown.
+    pub reference: String,
+    /// The path resolved against the directory of the Markdown file.
+    pub path: PathBuf,
+    /// Name the file is stored under in Anki, `None` when it cannot be read.
+    pub name: Option<String>,
+}
+
+impl MediaFile {
+    /// Base64 encoded content of the file, as `storeMediaFile` expects it.
+    pub fn data(&self) -> Result<String, String> {
+        fs::read(&self.path)
+            .map(|data| STANDARD.encode(data))
+            .map_err(|e| format!("Error reading '{}': {}", self.path.display(), e))
+    }
+}
+
+/// URLs, absolute paths and anchors are left to Anki.
+fn is_relative(reference: &str) -> bool {
+    !reference.contains(':') && !reference.starts_with(['/', '\\', '#'])
+}
+
+/// Names a file after the SHA-256 of its content, keeping its extension, so that files
+/// with the same name in different folders do not overwrite each other in Anki.
+fn media_name(path: &Path, data: &[u8]) -> String {
+    let hash: String = Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{}.{}", hash, ext.to_ascii_lowercase()),
+        None => hash,
+    }
+}
+
+/// Replaces the path of every relative image in `text` with the name the file is stored
+/// under in Anki, adding the images to `media`. Images whose file cannot be read keep
+/// their path, and images in code are only text.
+pub fn localize_images(text: &str, dir: &Path, media: &mut Vec<MediaFile>) -> String {
+    map_outside_fences(text, |text| localize_text(text, dir, media))
+}
+
+fn localize_text(text: &str, dir: &Path, media: &mut Vec<MediaFile>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['`', '!']) {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with('`') {
+            let end = code_span_end(rest);
+            result.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+        let Ok((remaining, (opening, reference))) = parse_image(rest) else {
+            result.push('!');
+            rest = &rest[1..];
+            continue;
+        };
+        let image = &rest[..rest.len() - remaining.len()];
+        rest = remaining;
+        if !is_relative(reference) {
+            result.push_str(image);
+            continue;
+        }
+
+        let path = dir.join(reference);
+        let name = fs::read(&path).ok().map(|data| media_name(&path, &data));
+        match &name {
+            Some(name) => {
+                result.push_str(opening);
+                result.push_str(name);
+                result.push_str(&image[opening.len() + reference.len()..]);
+            }
+            None => result.push_str(image),
+        }
+        if !media.iter().any(|file| file.reference == reference) {
+            media.push(MediaFile {
+                reference: reference.to_string(),
+                path,
+                name,
+            });
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Puts back the paths written in the Markdown in place of the names images are stored
+/// under in Anki.
+pub fn restore_image_paths(text: &str, media: &[MediaFile]) -> String {
+    media
+        .iter()
+        .fold(text.to_string(), |text, file| match &file.name {
+            Some(name) => text.replace(&format!("]({}", name), &format!("]({}", file.reference)),
+            None => text,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_images_localized() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("img")).unwrap();
+        fs::write(dir.path().join("img/foo.PNG"), b"png").unwrap();
+
+        let mut media = Vec::new();
+        let text = "See ![diagram](img/foo.PNG), ![again](img/foo.PNG), \
+                    ![web](https://example.com/a.png) and ![gone](missing.png).";
+        let localized = localize_images(text, dir.path(), &mut media);
+
+        let name = "8f8cbb7dcf46e0bc7d53265749a6c17d116093a6ba95e442764060c76fd4a86c.png";
+        assert_eq!(
+            localized,
+            format!(
+                "See ![diagram]({name}), ![again]({name}), \
+                 ![web](https://example.com/a.png) and ![gone](missing.png)."
+            )
+        );
+        assert_eq!(media.len(), 2);
+        assert_eq!(media[0].name.as_deref(), Some(name));
+        assert_eq!(media[1].reference, "missing.png");
+        assert_eq!(media[1].name, None);
+        assert_eq!(media[0].data(), Ok("cG5n".to_string()));
+        assert_eq!(restore_image_paths(&localized, &media), text);
+    }
+
+    #[test]
+    fn test_path_in_alt_text_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.png"), b"png").unwrap();
+        let name = "8f8cbb7dcf46e0bc7d53265749a6c17d116093a6ba95e442764060c76fd4a86c.png";
+        let localized = localize_images("![a.png](a.png)", dir.path(), &mut Vec::new());
+        assert_eq!(localized, format!("![a.png]({name})"));
+    }
+
+    #[test]
+    fn test_images_in_code_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.png"), b"png").unwrap();
+        let text = "Write `![x](a.png)`:\n\n```md\n![x](a.png)\n```\n";
+        let mut media = Vec::new();
+        assert_eq!(localize_images(text, dir.path(), &mut media), text);
+        assert!(media.is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+//! Notes synced from a file whose cards were since removed from it. Every synced note
+//! carries the source tag of its file, so they are found by that tag.
+
+use crate::anki::{AnkiClient, NoteInfo};
+use crate::types::MarkdownDocument;
+use std::collections::BTreeSet;
+
+/// Tag given to orphaned notes with `OrphanPolicy::Tag`.
+pub const ORPHANED_TAG: &str = "orphaned";
+
+/// What happens to orphaned notes once they are listed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OrphanPolicy {
+    /// Only list them.
+    #[default]
+    Report,
+    /// Suspend their cards.
+    Suspend,
+    /// Tag them with `orphaned`.
+    Tag,
+    Delete,
+}
+
+impl OrphanPolicy {
+    /// What was done to the notes, for the output of a run.
+    pub fn done(&self) -> &'static str {
+        match self {
+            OrphanPolicy::Report => "reported",
+            OrphanPolicy::Suspend => "suspended",
+            OrphanPolicy::Tag => "tagged as orphaned",
+            OrphanPolicy::Delete => "deleted",
+        }
+    }
+}
+
+/// First field of a note, to tell the user which note it is.
+pub fn note_front(info: &NoteInfo) -> &str {
+    info.field_names()
+        .first()
+        .map_or("", |name| info.fields[name].value.as_str())
+}
+
+/// IDs of the cards of `document`, synced or not.
+pub fn card_ids(document: &MarkdownDocument) -> impl Iterator<Item = u64> + '_ {
+    document
+        .blocks
+        .iter()
+        .filter_map(|block| block.metadata()?.id)
+}
+
+/// The notes with the source tag `tag` whose ID is not in `present`, the IDs of the cards
+/// of every file synced in the run. A card moved to another file of the run is
+/// therefore not an orphan of the file it left.
+pub fn find_orphans(
+    tag: &str,
+    present: &BTreeSet<u64>,
+    client: &impl AnkiClient,
+) -> Result<Vec<NoteInfo>, String> {
+    let orphans: Vec<u64> = client
+        .find_notes(&format!("tag:{}", tag))
+        .map_err(|e| format!("Cannot look for orphaned notes: {}", e))?
+        .into_iter()
+        .filter(|id| !present.contains(id))
+        .collect();
+    if orphans.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Anki reads `_` in a tag search as any character, so the tag is checked again.
+    let infos = client
+        .notes_info(&orphans)
+        .map_err(|e| format!("Cannot read orphaned notes: {}", e))?;
+    Ok(infos
+        .into_iter()
+        .filter(|info| info.tags.iter().any(|t| t == tag))
+        .collect())
+}
+
+/// Applies `policy` to the orphaned notes.
+pub fn handle_orphans(
+    orphans: &[NoteInfo],
+    policy: OrphanPolicy,
+    client: &impl AnkiClient,
+) -> Result<(), String> {
+    if orphans.is_empty() {
+        return Ok(());
+    }
+    let notes: Vec<u64> = orphans.iter().map(|info| info.note_id).collect();
+    let result = match policy {
+        OrphanPolicy::Report => Ok(()),
+        OrphanPolicy::Suspend => {
+            let cards: Vec<u64> = orphans.iter().flat_map(|info| info.cards.clone()).collect();
+            client.suspend(&cards).map(|_| ())
+        }
+        OrphanPolicy::Tag => client.add_tags(&notes, &[ORPHANED_TAG.to_string()]),
+        OrphanPolicy::Delete => client.delete_notes(&notes),
+    };
+    result.map_err(|e| format!("Cannot handle orphaned notes: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::Note;
+    use crate::anki::fake::FakeAnki;
+    use crate::parser::document::parse_document;
+    use indoc::indoc;
+
+    const TAG: &str = "markdown-anki-sync::notes.md";
+
+    fn tagged_note(anki: &FakeAnki, id: u64, front: &str, tag: &str) {
+        anki.insert_note(Note {
+            id: Some(id),
+            deck_name: "Default".to_string(),
+            model_name: "Basic".to_string(),
+            fields: [("Front".to_string(), front.to_string())].into(),
+            tags: vec![tag.to_string()],
+            options: None,
+        });
+    }
+
+    fn present(other: &[u64]) -> BTreeSet<u64> {
+        let (_, doc) = parse_document(indoc! {"
+            <!-- anki_id: 1 -->
+            ## Q: Kept?
+            Yes.
+
+            <!-- anki_id: 2, anki_sync: false -->
+            ## Q: Not synced?
+            Still here.
+        "})
+        .unwrap();
+        card_ids(&doc).chain(other.iter().copied()).collect()
+    }
+
+    fn orphans(anki: &FakeAnki) -> Vec<NoteInfo> {
+        find_orphans(TAG, &present(&[]), anki).unwrap()
+    }
+
+    fn anki() -> FakeAnki {
+        let anki = FakeAnki::new();
+        tagged_note(&anki, 1, "Kept?", TAG);
+        tagged_note(&anki, 2, "Not synced?", TAG);
+        tagged_note(&anki, 3, "Removed?", TAG);
+        tagged_note(&anki, 4, "Other file?", "markdown-anki-sync::other.md");
+        anki
+    }
+
+    #[test]
+    fn test_orphans_found() {
+        let anki = anki();
+        let orphans = orphans(&anki);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].note_id, 3);
+        assert_eq!(note_front(&orphans[0]), "Removed?");
+
+        handle_orphans(&orphans, OrphanPolicy::Report, &anki).unwrap();
+        assert!(anki.note(3).is_some());
+    }
+
+    #[test]
+    fn test_card_moved_to_other_file_not_orphaned() {
+        let anki = anki();
+        assert!(find_orphans(TAG, &present(&[3]), &anki).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_orphans_suspended_tagged_or_deleted() {
+        let anki = anki();
+        handle_orphans(&orphans(&anki), OrphanPolicy::Suspend, &anki).unwrap();
+        assert!(anki.is_suspended(30));
+        assert!(!anki.is_suspended(10));
+
+        handle_orphans(&orphans(&anki), OrphanPolicy::Tag, &anki).unwrap();
+        assert_eq!(anki.note(3).unwrap().tags, vec![TAG, ORPHANED_TAG]);
+
+        handle_orphans(&orphans(&anki), OrphanPolicy::Delete, &anki).unwrap();
+        assert!(anki.note(3).is_none());
+        assert!(orphans(&anki).is_empty());
+    }
+}
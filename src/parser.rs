@@ -1,6 +1,12 @@
 pub mod blank_line;
+pub mod cloze;
+pub mod code_fence;
 pub mod document;
+pub mod field_section;
 pub mod flashcard;
 pub mod flashcard_metadata;
 pub mod front_matter;
+pub mod heading;
+pub mod image;
 pub mod passthrough_block;
+pub mod tag;
@@ -0,0 +1,152 @@
+use super::flashcard::{parse_back, parse_header_hashes, trim_blank_lines};
+use crate::types::ClozeCard;
+use nom::{
+    IResult, Parser,
+    branch::alt,
+    bytes::complete::{tag, take_until},
+    character::complete::{digit1, line_ending, not_line_ending, space0, space1},
+    combinator::{eof, map_res, opt, recognize, verify},
+    sequence::delimited,
+};
+
+/// A `==highlighted==` span, which becomes a cloze deletion. The content may not start or
+/// end with whitespace, so comparisons such as `a == b` are left alone.
+pub fn parse_highlight(input: &str) -> IResult<&str, &str> {
+    delimited(
+        tag("=="),
+        verify(take_until("=="), |s: &str| {
+            !s.is_empty()
+                && !s.contains('\n')
+                && !s.starts_with(char::is_whitespace)
+                && !s.ends_with(char::is_whitespace)
+        }),
+        tag("=="),
+    )
+    .parse(input)
+}
+
+/// The opening of an explicit `{{c1::...}}` cloze deletion, returning its number.
+pub fn parse_cloze_number(input: &str) -> IResult<&str, u32> {
+    delimited(
+        tag("{{c"),
+        map_res(digit1, |s: &str| s.parse::<u32>()),
+        tag("::"),
+    )
+    .parse(input)
+}
+
+fn contains_deletion(text: &str) -> bool {
+    text.char_indices()
+        .any(|(i, _)| parse_highlight(&text[i..]).is_ok() || parse_cloze_number(&text[i..]).is_ok())
+}
+
+fn parse_text(input: &str) -> IResult<&str, (&str, u8)> {
+    let (input, level) = parse_header_hashes(input)?;
+    let (input, _) = space1.parse(input)?;
+    let (input, _) = tag("C:").parse(input)?;
+    let (input, _) = space0.parse(input)?;
+    let (input, text) = verify(not_line_ending, |s: &str| contains_deletion(s)).parse(input)?;
+    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
+    Ok((input, (text, level)))
+}
+
+/// Parses a `## C: ...` cloze card. The header line holds the text with its deletions;
+/// the optional body up to the next header of the same or a higher level becomes the
+/// Back Extra field.
+pub fn parse_cloze(input: &str) -> IResult<&str, ClozeCard> {
+    let start = input;
+    let (input, (text, header_level)) = parse_text(input)?;
+    let (input, back_extra) = opt(|i| parse_back(i, header_level)).parse(input)?;
+    let raw = &start[..start.len() - input.len()];
+    Ok((
+        input,
+        ClozeCard {
+            raw: raw.to_string(),
+            text: text.trim_end().to_string(),
+            back_extra: trim_blank_lines(back_extra.unwrap_or_default()).to_string(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(parse_highlight("==Paris== is"), Ok((" is", "Paris")));
+        assert!(parse_highlight("== Paris==").is_err());
+        assert!(parse_highlight("==Paris ==").is_err());
+        assert!(parse_highlight("====").is_err());
+    }
+
+    #[test]
+    fn test_cloze_number() {
+        assert_eq!(parse_cloze_number("{{c12::x}}"), Ok(("x}}", 12)));
+        assert!(parse_cloze_number("{{x::y}}").is_err());
+    }
+
+    #[test]
+    fn test_cloze_with_back_extra() {
+        let input = indoc! {"
+            ## C: The capital of ==France== is ==Paris==.
+            Paris has been the capital since 987.
+        "};
+        let (rest, card) = parse_cloze(input).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(card.text, "The capital of ==France== is ==Paris==.");
+        assert_eq!(card.back_extra, "Paris has been the capital since 987.\n");
+        assert_eq!(card.raw, input);
+    }
+
+    #[test]
+    fn test_cloze_without_back_extra() {
+        let input = indoc! {"
+            ## C: Rust was first released in {{c1::2015}}.
+
+            ## Next section
+        "};
+        let (rest, card) = parse_cloze(input).unwrap();
+        assert_eq!(rest, "## Next section\n");
+        assert_eq!(card.text, "Rust was first released in {{c1::2015}}.");
+        assert_eq!(card.back_extra, "");
+        assert_eq!(
+            card.raw,
+            "## C: Rust was first released in {{c1::2015}}.\n\n"
+        );
+    }
+
+    #[test]
+    fn test_cloze_at_eof() {
+        let input = "## C: ==Ferris== is the Rust mascot.";
+        let (rest, card) = parse_cloze(input).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(card.back_extra, "");
+        assert_eq!(card.raw, input);
+    }
+
+    #[test]
+    fn test_cloze_stops_before_metadata() {
+        let input = indoc! {"
+            ## C: ==Ferris== is the Rust mascot.
+            <!-- anki_id: 1 -->
+            ## Q: Next?
+            Yes.
+        "};
+        let (rest, card) = parse_cloze(input).unwrap();
+        assert_eq!(rest, "<!-- anki_id: 1 -->\n## Q: Next?\nYes.\n");
+        assert_eq!(card.back_extra, "");
+    }
+
+    #[test]
+    fn test_text_without_deletion_rejected() {
+        assert!(parse_cloze("## C: Nothing hidden here\nBody\n").is_err());
+        assert!(parse_cloze("## C: a == b\n").is_err());
+    }
+
+    #[test]
+    fn test_question_header_rejected() {
+        assert!(parse_cloze("## Q: ==Not== a cloze\nBody\n").is_err());
+    }
+}
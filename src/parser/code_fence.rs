@@ -0,0 +1,142 @@
+use nom::{
+    IResult, Parser,
+    branch::alt,
+    bytes::complete::take_while1,
+    character::complete::{line_ending, not_line_ending, space0},
+    combinator::{eof, recognize, rest, verify},
+    multi::many_till,
+};
+
+/// Up to three spaces of indentation, as CommonMark allows in front of a fence.
+fn fence_indent(input: &str) -> IResult<&str, &str> {
+    verify(space0, |s: &str| s.len() <= 3).parse(input)
+}
+
+fn end_of_line(input: &str) -> IResult<&str, &str> {
+    alt((recognize(line_ending), eof)).parse(input)
+}
+
+/// The opening line of a fence, returning its run of three or more backticks or tildes.
+/// The info string of a backtick fence may not contain backticks.
+fn parse_fence_open(input: &str) -> IResult<&str, &str> {
+    let (input, _) = fence_indent(input)?;
+    let (input, fence) = verify(
+        alt((take_while1(|c| c == '`'), take_while1(|c| c == '~'))),
+        |s: &str| s.len() >= 3,
+    )
+    .parse(input)?;
+    let (input, _) = verify(not_line_ending, |info: &str| {
+        !(fence.starts_with('`') && info.contains('`'))
+    })
+    .parse(input)?;
+    let (input, _) = end_of_line(input)?;
+    Ok((input, fence))
+}
+
+/// A closing line: the fence character at least as many times as the opening fence.
+fn fence_close(fence: &str) -> impl FnMut(&str) -> IResult<&str, &str> {
+    move |input: &str| {
+        let c = fence.chars().next().unwrap_or('`');
+        recognize((
+            fence_indent,
+            verify(take_while1(|t| t == c), |s: &str| s.len() >= fence.len()),
+            space0,
+            end_of_line,
+        ))
+        .parse(input)
+    }
+}
+
+fn any_line(input: &str) -> IResult<&str, &str> {
+    alt((recognize((not_line_ending, line_ending)), rest)).parse(input)
+}
+
+/// A fenced code block with backticks or tildes of any length, up to its closing fence
+/// or the end of the input when it is never closed. Nothing inside it is a card.
+pub fn parse_fenced_code(input: &str) -> IResult<&str, &str> {
+    let start = input;
+    let (input, fence) = parse_fence_open(input)?;
+    let (input, _) = many_till(any_line, alt((fence_close(fence), eof))).parse(input)?;
+    Ok((input, &start[..start.len() - input.len()]))
+}
+
+/// Runs `convert` on the text of `markdown` outside of fenced code blocks.
+pub fn map_outside_fences(markdown: &str, mut convert: impl FnMut(&str) -> String) -> String {
+    let mut result = String::with_capacity(markdown.len());
+    let mut text = String::new();
+    let mut rest = markdown;
+    while !rest.is_empty() {
+        if let Ok((remaining, code)) = parse_fenced_code(rest) {
+            result.push_str(&convert(&std::mem::take(&mut text)));
+            result.push_str(code);
+            rest = remaining;
+        } else {
+            let end = rest.find('\n').map_or(rest.len(), |i| i + 1);
+            text.push_str(&rest[..end]);
+            rest = &rest[end..];
+        }
+    }
+    result.push_str(&convert(&text));
+    result
+}
+
+/// End of the code span starting at `rest`, which runs up to the next backtick run of
+/// the same length. A run without a match is only its backticks.
+pub fn code_span_end(rest: &str) -> usize {
+    let ticks = rest.len() - rest.trim_start_matches('`').len();
+    let mut position = ticks;
+    while let Some(start) = rest[position..].find('`').map(|i| position + i) {
+        let len = rest[start..].len() - rest[start..].trim_start_matches('`').len();
+        if len == ticks {
+            return start + len;
+        }
+        position = start + len;
+    }
+    ticks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_backtick_fence() {
+        let input = indoc! {"
+            ```markdown
+            ## Q: example
+            <!-- anki_id: 1 -->
+            ```
+            After.
+        "};
+        let (rest, code) = parse_fenced_code(input).unwrap();
+        assert_eq!(rest, "After.\n");
+        assert_eq!(code, &input[..input.len() - rest.len()]);
+    }
+
+    #[test]
+    fn test_longer_fence_needs_longer_close() {
+        let input = indoc! {"
+            ~~~~
+            ~~~
+            ```
+            ~~~~~
+            After.
+        "};
+        let (rest, _) = parse_fenced_code(input).unwrap();
+        assert_eq!(rest, "After.\n");
+    }
+
+    #[test]
+    fn test_unclosed_fence_runs_to_end() {
+        let input = "```\n## Q: example\n";
+        assert_eq!(parse_fenced_code(input), Ok(("", input)));
+    }
+
+    #[test]
+    fn test_not_a_fence() {
+        assert!(parse_fenced_code("``code``\n").is_err());
+        assert!(parse_fenced_code("``` a`b\n").is_err());
+        assert!(parse_fenced_code("    ```\n").is_err());
+    }
+}
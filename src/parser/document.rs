@@ -5,6 +5,19 @@ pub fn parse_document(input: &str) -> IResult<&str, MarkdownDocument> {
     let (input, front_matter) = opt(super::front_matter::parse_front_matter).parse(input)?;
 
     let (input, blocks) = many0(alt((
+        |i| {
+            let (i, metadata) = super::flashcard_metadata::parse_flashcard_metadata(i)?;
+            let (i, blank) = opt(super::blank_line::parse_blank_line).parse(i)?;
+            let (i, cloze) = super::cloze::parse_cloze(i)?;
+            Ok((
+                i,
+                Block::ClozeWithMeta {
+                    metadata,
+                    blank_line: blank,
+                    cloze,
+                },
+            ))
+        },
         |i| {
             let (i, metadata) = super::flashcard_metadata::parse_flashcard_metadata(i)?;
             let (i, blank) = opt(super::blank_line::parse_blank_line).parse(i)?;
@@ -22,6 +35,10 @@ pub fn parse_document(input: &str) -> IResult<&str, MarkdownDocument> {
             let (i, card) = super::flashcard::parse_flashcard(i)?;
             Ok((i, Block::FlashCard(card)))
         },
+        |i| {
+            let (i, cloze) = super::cloze::parse_cloze(i)?;
+            Ok((i, Block::Cloze(cloze)))
+        },
         |i| {
             let (i, block) = super::passthrough_block::parse_passthrough_block(i)?;
             Ok((i, Block::Passthrough(block)))
@@ -40,9 +57,9 @@ pub fn parse_document(input: &str) -> IResult<&str, MarkdownDocument> {
 
 #[cfg(test)]
 mod tests {
-    use crate::types::FrontMatter;
 
     use super::*;
+    use crate::parser::field_section::split_field_sections;
     use indoc::indoc;
 
     #[test]
@@ -266,6 +283,75 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cloze_cards() {
+        let input = indoc! {"
+            ## C: The capital of ==France== is ==Paris==.
+
+            <!-- anki_id: 7 -->
+            ## C: Rust 1.0 was released in {{c1::2015}}.
+            Six weeks after the beta.
+        "};
+        let (rest, doc) = parse_document(input).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(doc.blocks.len(), 2);
+        match &doc.blocks[0] {
+            Block::Cloze(cloze) => {
+                assert_eq!(cloze.text, "The capital of ==France== is ==Paris==.");
+                assert_eq!(cloze.back_extra, "");
+            }
+            _ => panic!("Expected Block::Cloze"),
+        }
+        match &doc.blocks[1] {
+            Block::ClozeWithMeta {
+                metadata, cloze, ..
+            } => {
+                assert_eq!(metadata.id, Some(7));
+                assert_eq!(cloze.back_extra, "Six weeks after the beta.\n");
+            }
+            _ => panic!("Expected Block::ClozeWithMeta"),
+        }
+    }
+
+    #[test]
+    fn test_fenced_code_is_not_parsed() {
+        let input = indoc! {"
+            Writing a card:
+
+            ````markdown
+            <!-- anki_id: 1 -->
+            ## Q: What is Rust?
+            ```
+            ## Q: Still inside?
+            ````
+
+            ## Q: How is a card written?
+            Like this:
+
+            ~~~
+            ## Q: What is Rust?
+            ### Hint:
+            ~~~
+
+            ### Hint:
+            A header.
+        "};
+        let (rest, doc) = parse_document(input).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(doc.blocks.len(), 2);
+        assert!(matches!(&doc.blocks[0], Block::Passthrough(_)));
+        match &doc.blocks[1] {
+            Block::FlashCard(card) => {
+                assert_eq!(card.front, "How is a card written?");
+                assert!(card.back.contains("## Q: What is Rust?\n### Hint:\n~~~"));
+                let (_, sections) = split_field_sections(&card.back, |_| true);
+                assert_eq!(sections, "### Hint:\nA header.\n");
+            }
+            _ => panic!("Expected Block::FlashCard"),
+        }
+        assert_eq!(doc.raw(), input);
+    }
+
     #[test]
     fn test_round_trip() {
         let input = indoc! {"
@@ -289,11 +375,7 @@ mod tests {
 
         let mut reconstructed = String::new();
         if let Some(ref fm) = doc.front_matter {
-            match fm {
-                FrontMatter::Raw { raw } | FrontMatter::AnkiSync { raw, .. } => {
-                    reconstructed.push_str(raw);
-                }
-            }
+            reconstructed.push_str(fm.raw());
         }
         for block in &doc.blocks {
             match block {
@@ -311,6 +393,20 @@ mod tests {
                     }
                     reconstructed.push_str(&flashcard.raw);
                 }
+                Block::Cloze(cloze) => {
+                    reconstructed.push_str(&cloze.raw);
+                }
+                Block::ClozeWithMeta {
+                    metadata,
+                    blank_line,
+                    cloze,
+                } => {
+                    reconstructed.push_str(&metadata.raw);
+                    if let Some(bl) = blank_line {
+                        reconstructed.push_str(&bl.raw);
+                    }
+                    reconstructed.push_str(&cloze.raw);
+                }
                 Block::Passthrough(block) => {
                     reconstructed.push_str(&block.raw);
                 }
@@ -341,11 +437,7 @@ mod tests {
 
         let mut reconstructed = String::new();
         if let Some(ref fm) = doc.front_matter {
-            match fm {
-                FrontMatter::Raw { raw } | FrontMatter::AnkiSync { raw, .. } => {
-                    reconstructed.push_str(raw);
-                }
-            }
+            reconstructed.push_str(fm.raw());
         }
         for block in &doc.blocks {
             match block {
@@ -363,6 +455,20 @@ mod tests {
                     }
                     reconstructed.push_str(&flashcard.raw);
                 }
+                Block::Cloze(cloze) => {
+                    reconstructed.push_str(&cloze.raw);
+                }
+                Block::ClozeWithMeta {
+                    metadata,
+                    blank_line,
+                    cloze,
+                } => {
+                    reconstructed.push_str(&metadata.raw);
+                    if let Some(bl) = blank_line {
+                        reconstructed.push_str(&bl.raw);
+                    }
+                    reconstructed.push_str(&cloze.raw);
+                }
                 Block::Passthrough(block) => {
                     reconstructed.push_str(&block.raw);
                 }
@@ -0,0 +1,200 @@
+use super::code_fence::parse_fenced_code;
+use super::flashcard::{parse_back, parse_header_hashes, trim_blank_lines};
+use crate::types::FieldSection;
+use nom::{
+    IResult, Parser,
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{line_ending, not_line_ending, space0, space1},
+    combinator::{eof, opt, recognize, verify},
+};
+
+fn parse_section_header(input: &str) -> IResult<&str, (&str, u8)> {
+    let (input, level) = parse_header_hashes(input)?;
+    let (input, _) = space1.parse(input)?;
+    let (input, name) = verify(
+        take_while1(|c: char| c != ':' && c != '\n' && c != '\r'),
+        |s: &str| !s.trim().is_empty(),
+    )
+    .parse(input)?;
+    let (input, _) = tag(":").parse(input)?;
+    let (input, _) = space0.parse(input)?;
+    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
+    Ok((input, (name.trim(), level)))
+}
+
+fn parse_section(input: &str, is_field: impl Fn(&str) -> bool) -> IResult<&str, FieldSection> {
+    let (input, (name, level)) =
+        verify(parse_section_header, |(name, _)| is_field(name)).parse(input)?;
+    let (input, content) = opt(|i| parse_back(i, level)).parse(input)?;
+    Ok((
+        input,
+        FieldSection {
+            name: name.to_string(),
+            content: trim_blank_lines(content.unwrap_or_default()).to_string(),
+        },
+    ))
+}
+
+/// A line of the body, or a whole fenced code block so that headers inside it do not
+/// start a section.
+fn parse_line(input: &str) -> IResult<&str, &str> {
+    alt((
+        parse_fenced_code,
+        verify(recognize((not_line_ending, opt(line_ending))), |s: &str| {
+            !s.is_empty()
+        }),
+    ))
+    .parse(input)
+}
+
+/// Splits the body of a card into its own text and the named field sections it holds.
+/// A section starts with a header made of a field name and a colon, such as `### Extra:`,
+/// and runs until the next header of the same or a higher level. Headers whose name
+/// `is_field` rejects, such as `### Example:` on a note type without that field, stay
+/// in the text.
+pub fn parse_field_sections(
+    input: &str,
+    is_field: impl Fn(&str) -> bool,
+) -> (String, Vec<FieldSection>) {
+    let mut body = String::new();
+    let mut sections = Vec::new();
+    let mut input = input;
+
+    while !input.is_empty() {
+        if let Ok((rest, section)) = parse_section(input, &is_field) {
+            sections.push(section);
+            input = rest;
+        } else if let Ok((rest, line)) = parse_line(input) {
+            body.push_str(line);
+            input = rest;
+        } else {
+            break;
+        }
+    }
+
+    (trim_blank_lines(&body).to_string(), sections)
+}
+
+/// Splits the body of a card right before its first named field section, returning the
+/// text in front of it and the sections with everything after them.
+pub fn split_field_sections(input: &str, is_field: impl Fn(&str) -> bool) -> (&str, &str) {
+    let mut rest = input;
+    while !rest.is_empty() && parse_section(rest, &is_field).is_err() {
+        match parse_line(rest) {
+            Ok((remaining, _)) => rest = remaining,
+            Err(_) => break,
+        }
+    }
+    input.split_at(input.len() - rest.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn any_field(_: &str) -> bool {
+        true
+    }
+
+    fn section(name: &str, content: &str) -> FieldSection {
+        FieldSection {
+            name: name.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_no_sections() {
+        let input = "Just an answer.\n### A sub-header\nMore.\n";
+        assert_eq!(
+            parse_field_sections(input, any_field),
+            (input.to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_sections_after_body() {
+        let input = indoc! {"
+            The answer.
+
+            ### Extra:
+            Some extra text.
+
+            #### Detail
+            Still extra.
+
+            ### Source:
+            The Rust Book
+        "};
+        assert_eq!(
+            parse_field_sections(input, any_field),
+            (
+                "The answer.\n".to_string(),
+                vec![
+                    section("Extra", "Some extra text.\n\n#### Detail\nStill extra.\n"),
+                    section("Source", "The Rust Book\n"),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_empty_section() {
+        let input = "Answer.\n### Hint:\n### Source: \nBook\n";
+        assert_eq!(
+            parse_field_sections(input, any_field),
+            (
+                "Answer.\n".to_string(),
+                vec![section("Hint", ""), section("Source", "Book\n")]
+            )
+        );
+    }
+
+    #[test]
+    fn test_split_before_first_section() {
+        let input = "The answer.\n\n### Extra:\nMore.\n";
+        assert_eq!(
+            split_field_sections(input, any_field),
+            ("The answer.\n\n", "### Extra:\nMore.\n")
+        );
+        assert_eq!(
+            split_field_sections("No sections.\n", any_field),
+            ("No sections.\n", "")
+        );
+    }
+
+    #[test]
+    fn test_header_with_text_after_colon_is_not_a_section() {
+        let input = "Answer.\n### Note: this is prose\nMore.\n";
+        assert_eq!(
+            parse_field_sections(input, any_field),
+            (input.to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_header_of_unknown_field_stays_in_body() {
+        let input = indoc! {"
+            The answer.
+
+            ### Example:
+            `let x = 5;`
+
+            ### Extra:
+            More.
+        "};
+        assert_eq!(
+            parse_field_sections(input, |name| name == "Extra"),
+            (
+                "The answer.\n\n### Example:\n`let x = 5;`\n".to_string(),
+                vec![section("Extra", "More.\n")]
+            )
+        );
+        assert_eq!(
+            split_field_sections(input, |name| name == "Extra").1,
+            "### Extra:\nMore.\n"
+        );
+    }
+}
@@ -1,14 +1,16 @@
+use super::code_fence::parse_fenced_code;
 use crate::types::FlashCard;
 use nom::{
-    IResult, Parser,
+    IResult, Offset, Parser,
     branch::alt,
     bytes::complete::{tag, take_while1},
     character::complete::{line_ending, not_line_ending, space0, space1},
     combinator::{eof, not, recognize, rest, verify},
     multi::many1,
 };
+use std::ops::Range;
 
-fn parse_header_hashes(input: &str) -> IResult<&str, u8> {
+pub(super) fn parse_header_hashes(input: &str) -> IResult<&str, u8> {
     let (input, hashes) = verify(take_while1(|c| c == '#'), |s: &str| s.len() <= 6).parse(input)?;
     Ok((input, hashes.len() as u8))
 }
@@ -46,13 +48,19 @@ fn non_terminator_line(header_level: u8) -> impl FnMut(&str) -> IResult<&str, &s
     }
 }
 
-fn parse_back(input: &str, header_level: u8) -> IResult<&str, &str> {
-    recognize(many1(non_terminator_line(header_level))).parse(input)
+/// The body of a card up to the next header of the same or a higher level. Fenced code
+/// is taken as a whole, so headers and comments inside it never end the card.
+pub(super) fn parse_back(input: &str, header_level: u8) -> IResult<&str, &str> {
+    recognize(many1(alt((
+        parse_fenced_code,
+        non_terminator_line(header_level),
+    ))))
+    .parse(input)
 }
 
 /// Trims leading and trailing blank lines from a string, preserving interior blank lines.
 /// Returns a sub-slice of the input (zero-copy).
-fn trim_blank_lines(s: &str) -> &str {
+pub(super) fn trim_blank_lines(s: &str) -> &str {
     // Find first non-whitespace character, then back up to start of its line
     let start = match s.find(|c: char| !c.is_whitespace()) {
         Some(pos) => s[..pos].rfind('\n').map_or(0, |nl| nl + 1),
@@ -94,6 +102,15 @@ pub fn parse_flashcard(input: &str) -> IResult<&str, FlashCard> {
     ))
 }
 
+/// Byte range of the answer in the `raw` text of a card, as `parse_flashcard` reads it.
+pub fn back_range(raw: &str) -> Option<Range<usize>> {
+    let (input, (_, header_level)) = parse_front(raw).ok()?;
+    let (_, back_text) = parse_back(input, header_level).ok()?;
+    let back = trim_blank_lines(back_text);
+    let start = raw.offset(back);
+    Some(start..start + back.len())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -336,4 +353,13 @@ mod tests {
         "};
         assert!(parse_flashcard(input).is_err());
     }
+
+    #[test]
+    fn test_back_range() {
+        let input = "## Q: Back?\n\nBack?\n\n";
+        let (_, card) = parse_flashcard(input).unwrap();
+        let range = back_range(&card.raw).unwrap();
+        assert_eq!(range, 13..19);
+        assert_eq!(&card.raw[range], card.back);
+    }
 }
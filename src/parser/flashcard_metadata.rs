@@ -1,20 +1,47 @@
+use super::tag::parse_tag;
+use crate::anki::DuplicateScope;
 use crate::types::FlashCardMetaData;
 use nom::{
     IResult, Parser,
     branch::alt,
-    bytes::complete::{tag, take_while},
+    bytes::complete::{tag, take_while1},
     character::complete::{alphanumeric1, digit1},
     character::complete::{line_ending, not_line_ending, space0},
-    combinator::{eof, map_res, recognize, value},
-    multi::separated_list1,
-    sequence::{delimited, preceded},
+    combinator::{consumed, eof, map_res, peek, recognize, value, verify},
+    multi::{separated_list0, separated_list1},
+    sequence::{delimited, preceded, terminated},
 };
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// Keys this tool reads. Any other key is kept as it is written.
+const KNOWN_KEYS: [&str; 11] = [
+    "anki_id",
+    "anki_sync",
+    "anki_deck",
+    "anki_model",
+    "anki_tags",
+    "anki_tags_add",
+    "anki_tags_remove",
+    "anki_allow_duplicate",
+    "anki_duplicate_scope",
+    "anki_hash",
+    "anki_mod",
+];
 
 enum Field<'a> {
     Id(u64),
     Sync(bool),
-    Deck(&'a str),
-    Tags(Vec<&'a str>),
+    Deck(Cow<'a, str>),
+    Model(Cow<'a, str>),
+    Tags(Vec<Cow<'a, str>>),
+    TagsAdd(Vec<Cow<'a, str>>),
+    TagsRemove(Vec<Cow<'a, str>>),
+    AllowDuplicate(bool),
+    DuplicateScope(DuplicateScope),
+    Hash(&'a str),
+    Modified(u64),
+    Other(&'a str, &'a str),
 }
 
 // value parsers
@@ -22,14 +49,6 @@ fn parse_u64_digits(input: &str) -> IResult<&str, u64> {
     map_res(digit1, |s: &str| s.parse::<u64>()).parse(input)
 }
 
-fn parse_word_or_quoted_string(input: &str) -> IResult<&str, &str> {
-    alt((
-        alphanumeric1,
-        delimited(tag("\""), take_while(|c| c != '"' && c != '\n'), tag("\"")),
-    ))
-    .parse(input)
-}
-
 fn parse_bool(input: &str) -> IResult<&str, bool> {
     alt((value(true, tag("true")), value(false, tag("false")))).parse(input)
 }
@@ -47,66 +66,201 @@ fn parse_anki_id(input: &str) -> IResult<&str, u64> {
     parse_key_value("anki_id", parse_u64_digits).parse(input)
 }
 
-fn parse_anki_deck(input: &str) -> IResult<&str, &str> {
-    parse_key_value("anki_deck", parse_word_or_quoted_string).parse(input)
+fn parse_anki_deck(input: &str) -> IResult<&str, Cow<'_, str>> {
+    parse_key_value("anki_deck", parse_tag).parse(input)
+}
+
+fn parse_anki_model(input: &str) -> IResult<&str, Cow<'_, str>> {
+    parse_key_value("anki_model", parse_tag).parse(input)
 }
 
 fn parse_anki_sync(input: &str) -> IResult<&str, bool> {
     parse_key_value("anki_sync", parse_bool).parse(input)
 }
 
-fn parse_list(input: &str) -> IResult<&str, Vec<&str>> {
+fn parse_anki_allow_duplicate(input: &str) -> IResult<&str, bool> {
+    parse_key_value("anki_allow_duplicate", parse_bool).parse(input)
+}
+
+fn parse_duplicate_scope(input: &str) -> IResult<&str, DuplicateScope> {
+    alt((
+        value(DuplicateScope::Deck, tag("deck")),
+        value(DuplicateScope::Collection, tag("collection")),
+    ))
+    .parse(input)
+}
+
+fn parse_anki_duplicate_scope(input: &str) -> IResult<&str, DuplicateScope> {
+    parse_key_value("anki_duplicate_scope", parse_duplicate_scope).parse(input)
+}
+
+fn parse_anki_hash(input: &str) -> IResult<&str, &str> {
+    parse_key_value("anki_hash", alphanumeric1).parse(input)
+}
+
+fn parse_anki_mod(input: &str) -> IResult<&str, u64> {
+    parse_key_value("anki_mod", parse_u64_digits).parse(input)
+}
+
+fn parse_list(input: &str) -> IResult<&str, Vec<Cow<'_, str>>> {
     delimited(
         (tag("["), space0),
-        separated_list1((space0, tag(","), space0), parse_word_or_quoted_string),
+        separated_list0((space0, tag(","), space0), parse_tag),
         (space0, tag("]")),
     )
     .parse(input)
 }
 
-fn parse_anki_tags(input: &str) -> IResult<&str, Vec<&str>> {
+fn parse_anki_tags(input: &str) -> IResult<&str, Vec<Cow<'_, str>>> {
     parse_key_value("anki_tags", parse_list).parse(input)
 }
 
+fn parse_anki_tags_add(input: &str) -> IResult<&str, Vec<Cow<'_, str>>> {
+    parse_key_value("anki_tags_add", parse_list).parse(input)
+}
+
+fn parse_anki_tags_remove(input: &str) -> IResult<&str, Vec<Cow<'_, str>>> {
+    parse_key_value("anki_tags_remove", parse_list).parse(input)
+}
+
+fn parse_key(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-').parse(input)
+}
+
+// the end of a value: the next pair or the end of the comment
+fn value_end(input: &str) -> IResult<&str, (&str, &str)> {
+    peek((space0, alt((tag(","), tag("-->"))))).parse(input)
+}
+
+// any text up to the next `,` or `-->` on the line, without the spaces before them
+fn parse_text_value(input: &str) -> IResult<&str, &str> {
+    let line = &input[..input.find([',', '\n', '\r']).unwrap_or(input.len())];
+    let value = line[..line.find("-->").unwrap_or(line.len())].trim_end();
+    if value.is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TakeWhile1,
+        )));
+    }
+    Ok((&input[value.len()..], value))
+}
+
+// a key this tool does not know, with its value as written
+fn parse_other(input: &str) -> IResult<&str, (&str, &str)> {
+    let (input, key) = verify(parse_key, |key: &str| !KNOWN_KEYS.contains(&key)).parse(input)?;
+    let (input, _) = (space0, tag(":"), space0).parse(input)?;
+    let (input, value) = alt((
+        terminated(recognize(parse_list), value_end),
+        terminated(recognize(parse_tag), value_end),
+        parse_text_value,
+    ))
+    .parse(input)?;
+    Ok((input, (key, value)))
+}
+
 // parse specific fields
 fn parse_field(input: &str) -> IResult<&str, Field<'_>> {
     alt((
         |i| parse_anki_id(i).map(|(r, v)| (r, Field::Id(v))),
         |i| parse_anki_sync(i).map(|(r, v)| (r, Field::Sync(v))),
         |i| parse_anki_deck(i).map(|(r, v)| (r, Field::Deck(v))),
+        |i| parse_anki_model(i).map(|(r, v)| (r, Field::Model(v))),
         |i| parse_anki_tags(i).map(|(r, v)| (r, Field::Tags(v))),
+        |i| parse_anki_tags_add(i).map(|(r, v)| (r, Field::TagsAdd(v))),
+        |i| parse_anki_tags_remove(i).map(|(r, v)| (r, Field::TagsRemove(v))),
+        |i| parse_anki_allow_duplicate(i).map(|(r, v)| (r, Field::AllowDuplicate(v))),
+        |i| parse_anki_duplicate_scope(i).map(|(r, v)| (r, Field::DuplicateScope(v))),
+        |i| parse_anki_hash(i).map(|(r, v)| (r, Field::Hash(v))),
+        |i| parse_anki_mod(i).map(|(r, v)| (r, Field::Modified(v))),
+        |i| parse_other(i).map(|(r, (k, v))| (r, Field::Other(k, v))),
     ))
     .parse(input)
 }
 
-// parse the entire metadata comment
-pub fn parse_flashcard_metadata(input: &str) -> IResult<&str, FlashCardMetaData> {
-    let start = input;
+/// A `key: value` pair as it is written in a metadata comment.
+#[derive(Debug, PartialEq)]
+pub struct MetadataEntry<'a> {
+    pub key: &'a str,
+    /// Byte range of the whole pair in the comment.
+    pub span: Range<usize>,
+    /// Byte range of the value in the comment.
+    pub value: Range<usize>,
+}
+
+// the fields of a comment, each with the text it was parsed from
+fn parse_fields(input: &str) -> IResult<&str, Vec<(&str, Field<'_>)>> {
     let (input, fields) = delimited(
         (tag("<!--"), space0),
-        separated_list1((space0, tag(","), space0), parse_field),
+        separated_list1((space0, tag(","), space0), consumed(parse_field)),
         (space0, tag("-->")),
     )
     .parse(input)?;
     let (input, _) = not_line_ending(input)?;
     let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
+    Ok((input, fields))
+}
+
+/// The pairs of a metadata comment in the order they are written, for rewriting the
+/// comment in place.
+pub fn parse_metadata_entries(input: &str) -> IResult<&str, Vec<MetadataEntry<'_>>> {
+    let (rest, fields) = parse_fields(input)?;
+    let entries = fields
+        .into_iter()
+        .map(|(text, _)| {
+            let start = text.as_ptr() as usize - input.as_ptr() as usize;
+            let key = parse_key(text).map_or("", |(_, key)| key);
+            let after_key = text[key.len()..].trim_start_matches([' ', '\t']);
+            let value = after_key[1..].trim_start_matches([' ', '\t']);
+            MetadataEntry {
+                key,
+                span: start..start + text.len(),
+                value: start + text.len() - value.len()..start + text.len(),
+            }
+        })
+        .collect();
+    Ok((rest, entries))
+}
+
+// parse the entire metadata comment
+pub fn parse_flashcard_metadata(input: &str) -> IResult<&str, FlashCardMetaData> {
+    let start = input;
+    let (input, fields) = parse_fields(input)?;
+    // A comment with none of our keys is an ordinary comment.
+    if fields
+        .iter()
+        .all(|(_, field)| matches!(field, Field::Other(..)))
+    {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            start,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
 
     let raw = &start[..start.len() - input.len()];
 
-    let metadata = fields.into_iter().fold(
+    let metadata = fields.into_iter().map(|(_, field)| field).fold(
         FlashCardMetaData {
             raw: raw.to_string(),
-            id: None,
-            sync: None,
-            deck: None,
-            tags: None,
+            ..FlashCardMetaData::default()
         },
         |mut meta, f| {
             match f {
                 Field::Id(v) => meta.id = Some(v),
                 Field::Sync(v) => meta.sync = Some(v),
                 Field::Deck(v) => meta.deck = Some(v.to_string()),
+                Field::Model(v) => meta.model = Some(v.to_string()),
                 Field::Tags(v) => meta.tags = Some(v.into_iter().map(String::from).collect()),
+                Field::TagsAdd(v) => {
+                    meta.tags_add = Some(v.into_iter().map(String::from).collect())
+                }
+                Field::TagsRemove(v) => {
+                    meta.tags_remove = Some(v.into_iter().map(String::from).collect())
+                }
+                Field::AllowDuplicate(v) => meta.allow_duplicate = Some(v),
+                Field::DuplicateScope(v) => meta.duplicate_scope = Some(v),
+                Field::Hash(v) => meta.hash = Some(v.to_string()),
+                Field::Modified(v) => meta.modified = Some(v),
+                Field::Other(k, v) => meta.other.push((k.to_string(), v.to_string())),
             }
             meta
         },
@@ -118,6 +272,8 @@ pub fn parse_flashcard_metadata(input: &str) -> IResult<&str, FlashCardMetaData>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parser::tag::any_value;
+    use proptest::prelude::*;
 
     #[test]
     fn test_parse_u64_digits() {
@@ -139,6 +295,15 @@ mod tests {
         assert_eq!(meta.deck.as_deref(), Some("hello"));
     }
 
+    #[test]
+    fn test_sync_state() {
+        let input = "<!-- anki_id: 7, anki_hash: 0f1e2d3c4b5a6978, anki_mod: 1700000000 -->";
+        let (rest, meta) = parse_flashcard_metadata(input).expect("Should parse");
+        assert_eq!(rest, "");
+        assert_eq!(meta.hash.as_deref(), Some("0f1e2d3c4b5a6978"));
+        assert_eq!(meta.modified, Some(1700000000));
+    }
+
     #[test]
     fn test_different_order() {
         let input = "<!-- anki_deck: \"My Deck\", anki_id: 42, anki_sync: false -->";
@@ -205,6 +370,118 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hierarchical_tags() {
+        let input = "<!-- anki_id: 5, anki_tags: [rust::ownership, ch-3, a/b, v1.2, élő] -->";
+        let (rest, meta) = parse_flashcard_metadata(input).expect("Should parse");
+        assert_eq!(rest, "");
+        assert_eq!(meta.id, Some(5));
+        assert_eq!(
+            meta.tags,
+            Some(vec![
+                "rust::ownership".to_string(),
+                "ch-3".to_string(),
+                "a/b".to_string(),
+                "v1.2".to_string(),
+                "élő".to_string()
+            ])
+        );
+        assert_eq!(meta.formatted().raw, input);
+    }
+
+    #[test]
+    fn test_tags_added_and_removed() {
+        let input = "<!-- anki_tags_add: [hard], anki_tags_remove: [lang, \"to do\"] -->";
+        let (rest, meta) = parse_flashcard_metadata(input).expect("Should parse");
+        assert_eq!(rest, "");
+        assert_eq!(meta.tags, None);
+        assert_eq!(meta.tags_add, Some(vec!["hard".to_string()]));
+        assert_eq!(
+            meta.tags_remove,
+            Some(vec!["lang".to_string(), "to do".to_string()])
+        );
+        assert_eq!(meta.formatted().raw, input);
+    }
+
+    #[test]
+    fn test_duplicate_options() {
+        let input = "<!-- anki_allow_duplicate: false, anki_duplicate_scope: deck -->\n";
+        let (rest, meta) = parse_flashcard_metadata(input).expect("Should parse");
+        assert_eq!(rest, "");
+        assert_eq!(meta.allow_duplicate, Some(false));
+        assert_eq!(meta.duplicate_scope, Some(DuplicateScope::Deck));
+        assert_eq!(meta.formatted().raw, input);
+        assert!(parse_flashcard_metadata("<!-- anki_duplicate_scope: world -->").is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys_kept() {
+        let input = "<!-- anki_note: x, anki_id: 3, source: [book, \"ch 2\"] -->\n";
+        let (rest, meta) = parse_flashcard_metadata(input).expect("Should parse");
+        assert_eq!(rest, "");
+        assert_eq!(meta.id, Some(3));
+        assert_eq!(
+            meta.other,
+            vec![
+                ("anki_note".to_string(), "x".to_string()),
+                ("source".to_string(), "[book, \"ch 2\"]".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_value_with_spaces_kept() {
+        let input = "<!-- anki_note: my note, anki_id: 3, source: The Rust Book  -->\n";
+        let (rest, meta) = parse_flashcard_metadata(input).expect("Should parse");
+        assert_eq!(rest, "");
+        assert_eq!(meta.id, Some(3));
+        assert_eq!(
+            meta.other,
+            vec![
+                ("anki_note".to_string(), "my note".to_string()),
+                ("source".to_string(), "The Rust Book".to_string())
+            ]
+        );
+        let synced = FlashCardMetaData {
+            id: Some(4),
+            ..meta
+        };
+        assert_eq!(
+            synced.formatted().raw,
+            "<!-- anki_note: my note, anki_id: 4, source: The Rust Book  -->\n"
+        );
+    }
+
+    #[test]
+    fn test_comment_without_known_keys_is_not_metadata() {
+        assert!(parse_flashcard_metadata("<!-- TODO: rewrite -->\n").is_err());
+    }
+
+    #[test]
+    fn test_formatting_kept_when_id_added() {
+        let input = "<!--anki_deck :  \"Geo\" ,anki_note: x,anki_tags: [a,b]   -->\n";
+        let (_, meta) = parse_flashcard_metadata(input).expect("Should parse");
+        let synced = FlashCardMetaData {
+            id: Some(12),
+            ..meta.clone()
+        }
+        .formatted();
+        assert_eq!(
+            synced.raw,
+            "<!--anki_deck :  \"Geo\" ,anki_note: x,anki_tags: [a,b] ,anki_id: 12   -->\n"
+        );
+        let moved = FlashCardMetaData {
+            deck: Some("Lang::Rust".to_string()),
+            tags: None,
+            ..meta
+        }
+        .formatted();
+        assert_eq!(
+            moved.raw,
+            "<!--anki_deck :  Lang::Rust ,anki_note: x   -->\n"
+        );
+    }
+
     #[test]
     fn test_tags_with_quoted_strings() {
         let input = r#"<!-- anki_tags: [tag1, "tag two", tag3] -->"#;
@@ -252,8 +529,59 @@ mod tests {
     }
 
     #[test]
-    fn test_tags_empty_list_fails() {
-        let input = "<!-- anki_tags: [] -->";
-        assert!(parse_flashcard_metadata(input).is_err());
+    fn test_model() {
+        let input = r#"<!-- anki_id: 5, anki_model: "Basic (and reversed card)" -->"#;
+        let (rest, meta) = parse_flashcard_metadata(input).expect("Should parse");
+        assert_eq!(rest, "");
+        assert_eq!(meta.id, Some(5));
+        assert_eq!(meta.model.as_deref(), Some("Basic (and reversed card)"));
+    }
+
+    #[test]
+    fn test_tags_empty_list() {
+        for input in [
+            "<!-- anki_id: 5, anki_tags: [] -->",
+            "<!-- anki_id: 5, anki_tags: [ ] -->",
+        ] {
+            let (rest, meta) = parse_flashcard_metadata(input).expect("Should parse");
+            assert_eq!(rest, "");
+            assert_eq!(meta.id, Some(5));
+            assert_eq!(meta.tags, Some(vec![]));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_written_metadata_parses_back(
+            id in any::<u64>(),
+            sync in any::<Option<bool>>(),
+            deck in proptest::option::of(any_value()),
+            model in proptest::option::of(any_value()),
+            tags in proptest::option::of(proptest::collection::vec(any_value(), 0..4)),
+            hash in proptest::option::of("[0-9a-f]{16}"),
+            modified in any::<Option<u64>>(),
+        ) {
+            let written = FlashCardMetaData {
+                id: Some(id),
+                sync,
+                deck,
+                model,
+                tags,
+                hash,
+                modified,
+                ..FlashCardMetaData::default()
+            }
+            .formatted();
+            let (rest, meta) = parse_flashcard_metadata(&written.raw).expect("Should parse");
+            prop_assert_eq!(rest, "");
+            prop_assert_eq!(&meta.raw, &written.raw);
+            prop_assert_eq!(meta.id, written.id);
+            prop_assert_eq!(meta.sync, written.sync);
+            prop_assert_eq!(meta.deck, written.deck);
+            prop_assert_eq!(meta.model, written.model);
+            prop_assert_eq!(meta.tags, written.tags);
+            prop_assert_eq!(meta.hash, written.hash);
+            prop_assert_eq!(meta.modified, written.modified);
+        }
     }
 }
@@ -1,18 +1,15 @@
-use crate::types::FrontMatter;
+use crate::types::{FrontMatter, SyncSettings};
 use nom::{
     IResult, Parser,
     branch::alt,
-    bytes::complete::{tag, take_until, take_while, take_while1},
-    character::complete::{alphanumeric1, line_ending, not_line_ending, space0, space1},
-    combinator::{eof, opt, recognize},
-    multi::{many0, many1, separated_list1},
-    sequence::delimited,
+    bytes::complete::tag,
+    character::complete::{line_ending, not_line_ending, space0},
+    combinator::{eof, not, recognize, rest},
+    multi::many_till,
 };
+use serde_norway::Value;
 
-enum AnkiSyncField {
-    Deck(String),
-    Tags(Vec<String>),
-}
+const SETTINGS_KEY: &str = "anki_sync";
 
 // --- Delimiter and raw extraction ---
 
@@ -22,214 +19,119 @@ fn parse_front_matter_delimiter(input: &str) -> IResult<&str, &str> {
     Ok((input, "---"))
 }
 
-fn parse_raw_front_matter(input: &str) -> IResult<&str, &str> {
-    let start = input;
-    let (input, _) = parse_front_matter_delimiter(input)?;
-    let (input, _content) = take_until("---").parse(input)?;
-    let (input, _) = parse_front_matter_delimiter(input)?;
-    // Consume optional trailing newline after closing delimiter
-    let (input, _) = opt(line_ending).parse(input)?;
-    let raw = &start[..start.len() - input.len()];
-    Ok((input, raw))
-}
-
-// --- Value parsers ---
-
-fn parse_rest_of_line(input: &str) -> IResult<&str, &str> {
-    let (input, line) = not_line_ending.parse(input)?;
-    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
-    let trimmed = line.trim();
-    if trimmed.is_empty() {
-        return Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Verify,
-        )));
-    }
-    Ok((input, trimmed))
-}
-
-fn parse_word_or_quoted_string(input: &str) -> IResult<&str, &str> {
-    alt((
-        alphanumeric1,
-        delimited(tag("\""), take_while(|c| c != '"' && c != '\n'), tag("\"")),
+/// The closing line of the front matter: `---` or `...` and nothing else.
+fn parse_closing_delimiter(input: &str) -> IResult<&str, &str> {
+    recognize((
+        alt((tag("---"), tag("..."))),
+        space0,
+        alt((recognize(line_ending), eof)),
     ))
     .parse(input)
 }
 
-fn parse_flow_list(input: &str) -> IResult<&str, Vec<&str>> {
-    delimited(
-        (tag("["), space0),
-        separated_list1((space0, tag(","), space0), parse_word_or_quoted_string),
-        (space0, tag("]")),
-    )
-    .parse(input)
-}
-
-fn parse_block_list_item(input: &str) -> IResult<&str, &str> {
-    let (input, _) = space1.parse(input)?;
-    let (input, _) = tag("-").parse(input)?;
-    let (input, _) = space1.parse(input)?;
-    let (input, value) = parse_word_or_quoted_string(input)?;
-    let (input, _) = space0.parse(input)?;
-    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
-    Ok((input, value))
-}
-
-fn parse_block_list(input: &str) -> IResult<&str, Vec<&str>> {
-    many1(parse_block_list_item).parse(input)
-}
-
-// --- Field parsers ---
-
-fn parse_deck_field(input: &str) -> IResult<&str, &str> {
-    let (input, _) = space1.parse(input)?;
-    let (input, _) = tag("deck:").parse(input)?;
-    let (input, _) = space0.parse(input)?;
-    let (input, value) = parse_rest_of_line(input)?;
-    Ok((input, value))
+fn any_line(input: &str) -> IResult<&str, &str> {
+    alt((recognize((not_line_ending, line_ending)), rest)).parse(input)
 }
 
-fn parse_tags_block_variant(input: &str) -> IResult<&str, Vec<&str>> {
-    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
-    parse_block_list(input)
-}
-
-fn parse_tags_flow_variant(input: &str) -> IResult<&str, Vec<&str>> {
-    let (input, list) = parse_flow_list(input)?;
-    let (input, _) = space0.parse(input)?;
-    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
-    Ok((input, list))
-}
-
-fn parse_tags_field(input: &str) -> IResult<&str, Vec<&str>> {
-    let (input, _) = space1.parse(input)?;
-    let (input, _) = tag("tags:").parse(input)?;
-    let (input, _) = space0.parse(input)?;
-    alt((parse_tags_block_variant, parse_tags_flow_variant)).parse(input)
-}
-
-fn parse_anki_sync_field(input: &str) -> IResult<&str, AnkiSyncField> {
-    alt((
-        |i| parse_deck_field(i).map(|(r, v)| (r, AnkiSyncField::Deck(v.to_string()))),
+/// Returns the whole front matter, delimiters included, and the YAML between them.
+fn parse_raw_front_matter(input: &str) -> IResult<&str, (&str, &str)> {
+    let start = input;
+    let (input, _) = parse_front_matter_delimiter(input)?;
+    let content_start = input;
+    let (input, (lines, _)) = many_till(
         |i| {
-            parse_tags_field(i).map(|(r, v)| {
-                (
-                    r,
-                    AnkiSyncField::Tags(v.into_iter().map(String::from).collect()),
-                )
-            })
+            let (i, _) = not(eof).parse(i)?;
+            any_line(i)
         },
-    ))
-    .parse(input)
-}
-
-// --- Skip helpers ---
-
-fn is_indented_line(input: &str) -> bool {
-    input.starts_with(' ') || input.starts_with('\t')
+        parse_closing_delimiter,
+    )
+    .parse(input)?;
+    let content_len: usize = lines.iter().map(|line| line.len()).sum();
+    let raw = &start[..start.len() - input.len()];
+    Ok((input, (raw, &content_start[..content_len])))
 }
 
-fn skip_indented_line(input: &str) -> IResult<&str, ()> {
-    let (input, _) = take_while1(|c| c == ' ' || c == '\t').parse(input)?;
-    let (input, _) = not_line_ending.parse(input)?;
-    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
-    Ok((input, ()))
-}
+// --- Settings ---
 
-fn skip_non_anki_sync_line(input: &str) -> IResult<&str, ()> {
-    // Must be a non-indented, non-empty line that doesn't start with "anki_sync:"
-    if input.is_empty() || input.starts_with("anki_sync:") {
-        return Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Verify,
-        )));
-    }
-    if is_indented_line(input) {
-        return Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Verify,
-        )));
+/// The `anki_sync` settings in the YAML, `None` when there are none. YAML that cannot be
+/// read only counts as an error when it mentions `anki_sync`; otherwise it is not ours.
+fn parse_settings(yaml: &str) -> Result<Option<SyncSettings>, String> {
+    let mut value: Value = match serde_norway::from_str(yaml) {
+        Ok(value) => value,
+        Err(_) if !yaml.contains(SETTINGS_KEY) => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+    value.apply_merge().map_err(|e| e.to_string())?;
+    let mut settings: SyncSettings = match value.get(SETTINGS_KEY) {
+        None | Some(Value::Null) => return Ok(None),
+        Some(settings) => serde_norway::from_value(settings.clone())
+            .map_err(|e| format!("{}: {}", SETTINGS_KEY, e))?,
+    };
+    if settings.front_matter_tags {
+        for tag in front_matter_tags(&value) {
+            if !settings.tags.contains(&tag) {
+                settings.tags.push(tag);
+            }
+        }
     }
-    let (input, _) = not_line_ending.parse(input)?;
-    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
-    // Skip any indented children
-    let (input, _) = many0(skip_indented_line).parse(input)?;
-    Ok((input, ()))
+    Ok(Some(settings))
 }
 
-fn skip_blank_line(input: &str) -> IResult<&str, ()> {
-    let (input, _) = line_ending.parse(input)?;
-    Ok((input, ()))
+/// An Obsidian tag or alias as an Anki tag: without a leading `#`, with nested tags such
+/// as `area/topic` as `area::topic`, and with `_` for spaces, which Anki tags cannot hold.
+fn obsidian_tag(text: &str) -> String {
+    text.trim()
+        .trim_start_matches('#')
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .replace('/', "::")
 }
 
-fn skip_non_anki_sync_lines(input: &str) -> IResult<&str, ()> {
-    let (input, _) = many0(alt((skip_non_anki_sync_line, skip_blank_line))).parse(input)?;
-    Ok((input, ()))
+/// The top-level `tags` and `aliases` of Obsidian and Jekyll as Anki tags. Either can be
+/// a list or a single string; a string of tags is split at commas and spaces, a string
+/// of aliases only at commas.
+fn front_matter_tags(value: &Value) -> Vec<String> {
+    let values = |key: &str, separators: &[char]| -> Vec<String> {
+        match value.get(key) {
+            Some(Value::Sequence(items)) => items
+                .iter()
+                .filter_map(|item| match item {
+                    Value::String(text) => Some(text.clone()),
+                    Value::Number(number) => Some(number.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            Some(Value::String(text)) => text.split(separators).map(String::from).collect(),
+            _ => Vec::new(),
+        }
+    };
+    values("tags", &[',', ' '])
+        .into_iter()
+        .chain(values("aliases", &[',']))
+        .map(|text| obsidian_tag(&text))
+        .filter(|tag| !tag.is_empty())
+        .collect()
 }
 
 // --- Main parser ---
 
-fn parse_anki_sync_content(input: &str) -> IResult<&str, (Option<String>, Vec<String>)> {
-    // Skip any non-anki_sync top-level keys
-    let (input, _) = skip_non_anki_sync_lines(input)?;
-
-    if input.is_empty() || !input.starts_with("anki_sync:") {
-        return Ok((input, (None, vec![])));
-    }
-
-    // Consume the "anki_sync:" line
-    let (input, _) = tag("anki_sync:").parse(input)?;
-    let (input, _) = space0.parse(input)?;
-    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
-
-    // Parse indented fields under anki_sync
-    let (input, fields) = many0(parse_anki_sync_field).parse(input)?;
-
-    let (deck, tags) = fields
-        .into_iter()
-        .fold((None, vec![]), |(mut deck, mut tags), field| {
-            match field {
-                AnkiSyncField::Deck(d) => deck = Some(d),
-                AnkiSyncField::Tags(t) => tags = t,
-            }
-            (deck, tags)
-        });
-
-    Ok((input, (deck, tags)))
-}
-
 pub fn parse_front_matter(input: &str) -> IResult<&str, FrontMatter> {
-    let (remaining, raw) = parse_raw_front_matter(input)?;
-
-    // Second pass: parse the YAML content (between the delimiters)
-    let inner_start = raw.find('\n').map(|i| i + 1).unwrap_or(raw.len());
-    let inner_end = raw.rfind("---").unwrap_or(raw.len());
-    let inner = &raw[inner_start..inner_end];
-
-    let (deck, tags) = match parse_anki_sync_content(inner) {
-        Ok((_, (deck, tags))) => (deck, tags),
-        Err(_) => (None, vec![]),
+    let (remaining, (raw, yaml)) = parse_raw_front_matter(input)?;
+    let raw = raw.to_string();
+    let front_matter = match parse_settings(yaml) {
+        Ok(Some(settings)) => FrontMatter::AnkiSync { raw, settings },
+        Ok(None) => FrontMatter::Raw { raw },
+        Err(error) => FrontMatter::Invalid { raw, error },
     };
-
-    let raw_string = raw.to_string();
-
-    if deck.is_some() || !tags.is_empty() {
-        Ok((
-            remaining,
-            FrontMatter::AnkiSync {
-                raw: raw_string,
-                deck,
-                tags,
-            },
-        ))
-    } else {
-        Ok((remaining, FrontMatter::Raw { raw: raw_string }))
-    }
+    Ok((remaining, front_matter))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::anki::DuplicateScope;
+    use crate::render::RenderMode;
     use indoc::indoc;
 
     #[test]
@@ -245,9 +147,9 @@ mod tests {
         let (rest, fm) = parse_front_matter(input).unwrap();
         assert_eq!(rest, "rest\n");
         match fm {
-            FrontMatter::AnkiSync { deck, tags, .. } => {
-                assert_eq!(deck.as_deref(), Some("My Deck Name"));
-                assert_eq!(tags, vec!["tag1", "tag2"]);
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.deck.as_deref(), Some("My Deck Name"));
+                assert_eq!(settings.tags, vec!["tag1", "tag2"]);
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -268,9 +170,9 @@ mod tests {
         let (rest, fm) = parse_front_matter(input).unwrap();
         assert_eq!(rest, "rest\n");
         match fm {
-            FrontMatter::AnkiSync { deck, tags, .. } => {
-                assert_eq!(deck.as_deref(), Some("My Deck Name"));
-                assert_eq!(tags, vec!["tag1", "tag2"]);
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.deck.as_deref(), Some("My Deck Name"));
+                assert_eq!(settings.tags, vec!["tag1", "tag2"]);
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -287,9 +189,9 @@ mod tests {
         let (rest, fm) = parse_front_matter(input).unwrap();
         assert_eq!(rest, "");
         match fm {
-            FrontMatter::AnkiSync { deck, tags, .. } => {
-                assert_eq!(deck.as_deref(), Some("MyDeck"));
-                assert!(tags.is_empty());
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.deck.as_deref(), Some("MyDeck"));
+                assert!(settings.tags.is_empty());
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -306,9 +208,9 @@ mod tests {
         let (rest, fm) = parse_front_matter(input).unwrap();
         assert_eq!(rest, "");
         match fm {
-            FrontMatter::AnkiSync { deck, tags, .. } => {
-                assert_eq!(deck, None);
-                assert_eq!(tags, vec!["tag1", "tag2"]);
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.deck, None);
+                assert_eq!(settings.tags, vec!["tag1", "tag2"]);
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -327,9 +229,9 @@ mod tests {
         let (rest, fm) = parse_front_matter(input).unwrap();
         assert_eq!(rest, "");
         match fm {
-            FrontMatter::AnkiSync { deck, tags, .. } => {
-                assert_eq!(deck, None);
-                assert_eq!(tags, vec!["alpha", "beta"]);
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.deck, None);
+                assert_eq!(settings.tags, vec!["alpha", "beta"]);
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -345,8 +247,11 @@ mod tests {
         "};
         let (_, fm) = parse_front_matter(input).unwrap();
         match fm {
-            FrontMatter::AnkiSync { deck, .. } => {
-                assert_eq!(deck.as_deref(), Some("My Long Deck Name With Spaces"));
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(
+                    settings.deck.as_deref(),
+                    Some("My Long Deck Name With Spaces")
+                );
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -362,8 +267,29 @@ mod tests {
         "#};
         let (_, fm) = parse_front_matter(input).unwrap();
         match fm {
-            FrontMatter::AnkiSync { tags, .. } => {
-                assert_eq!(tags, vec!["tag1", "tag two", "tag3"]);
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.tags, vec!["tag1", "tag two", "tag3"]);
+            }
+            _ => panic!("Expected AnkiSync"),
+        }
+    }
+
+    #[test]
+    fn test_hierarchical_tags_and_heading_tags() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              tags:
+                - lang::rust
+                - spaced-repetition
+              heading_tags: true
+            ---
+        "};
+        let (_, fm) = parse_front_matter(input).unwrap();
+        match fm {
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.tags, vec!["lang::rust", "spaced-repetition"]);
+                assert!(settings.heading_tags);
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -382,8 +308,8 @@ mod tests {
         "#};
         let (_, fm) = parse_front_matter(input).unwrap();
         match fm {
-            FrontMatter::AnkiSync { tags, .. } => {
-                assert_eq!(tags, vec!["tag1", "tag two", "tag3"]);
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.tags, vec!["tag1", "tag two", "tag3"]);
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -403,9 +329,28 @@ mod tests {
         "};
         let (_, fm) = parse_front_matter(input).unwrap();
         match fm {
-            FrontMatter::AnkiSync { deck, tags, .. } => {
-                assert_eq!(deck.as_deref(), Some("TestDeck"));
-                assert_eq!(tags, vec!["a", "b"]);
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.deck.as_deref(), Some("TestDeck"));
+                assert_eq!(settings.tags, vec!["a", "b"]);
+            }
+            _ => panic!("Expected AnkiSync"),
+        }
+    }
+
+    #[test]
+    fn test_model() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              model: Basic (and reversed card)
+            ---
+        "};
+        let (_, fm) = parse_front_matter(input).unwrap();
+        match fm {
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.deck, None);
+                assert_eq!(settings.model.as_deref(), Some("Basic (and reversed card)"));
+                assert!(settings.tags.is_empty());
             }
             _ => panic!("Expected AnkiSync"),
         }
@@ -518,9 +463,179 @@ mod tests {
         "};
         let (_, fm) = parse_front_matter(input).unwrap();
         match fm {
-            FrontMatter::AnkiSync { deck, .. } => {
-                assert_eq!(deck.as_deref(), Some("Found"));
+            FrontMatter::AnkiSync { settings, .. } => {
+                assert_eq!(settings.deck.as_deref(), Some("Found"));
+            }
+            _ => panic!("Expected AnkiSync"),
+        }
+    }
+
+    #[test]
+    fn test_yaml_comments_anchors_and_dashes() {
+        let input = indoc! {"
+            ---
+            # Obsidian properties
+            title: Notes --- draft
+            base: &base
+              deck: Rust::Ownership # the deck
+            anki_sync:
+              <<: *base
+              tags: [a]
+            ---
+            rest
+        "};
+        let (rest, fm) = parse_front_matter(input).unwrap();
+        assert_eq!(rest, "rest\n");
+        match fm {
+            FrontMatter::AnkiSync { raw, settings } => {
+                assert_eq!(raw, &input[..input.len() - rest.len()]);
+                assert_eq!(settings.deck.as_deref(), Some("Rust::Ownership"));
+                assert_eq!(settings.tags, vec!["a"]);
+            }
+            _ => panic!("Expected AnkiSync"),
+        }
+    }
+
+    #[test]
+    fn test_all_settings() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              deck: Rust
+              model: Custom
+              tags: [book]
+              heading_tags: true
+              sync: false
+              allow_duplicates: true
+              duplicate_scope: deck
+              render: raw
+              tag_prefix: notes
+              deck_from_path: true
+            ---
+        "};
+        let (_, fm) = parse_front_matter(input).unwrap();
+        match fm {
+            FrontMatter::AnkiSync { settings, .. } => assert_eq!(
+                settings,
+                SyncSettings {
+                    deck: Some("Rust".to_string()),
+                    model: Some("Custom".to_string()),
+                    tags: vec!["book".to_string()],
+                    heading_tags: true,
+                    front_matter_tags: false,
+                    sync: false,
+                    allow_duplicates: true,
+                    duplicate_scope: Some(DuplicateScope::Deck),
+                    render: Some(RenderMode::Raw),
+                    tag_prefix: Some("notes".to_string()),
+                    deck_from_path: true,
+                }
+            ),
+            _ => panic!("Expected AnkiSync"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              sync: sometimes
+            ---
+        "};
+        let (_, fm) = parse_front_matter(input).unwrap();
+        match fm {
+            FrontMatter::Invalid { error, .. } => assert!(error.starts_with("anki_sync: ")),
+            _ => panic!("Expected Invalid"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_setting_is_invalid() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              dek: Rust
+            ---
+        "};
+        let (_, fm) = parse_front_matter(input).unwrap();
+        match fm {
+            FrontMatter::Invalid { error, .. } => {
+                assert!(error.starts_with("anki_sync: unknown field `dek`"))
+            }
+            _ => panic!("Expected Invalid"),
+        }
+    }
+
+    #[test]
+    fn test_text_between_rules_is_raw() {
+        let input = "---\nNot: really: yaml\n---\n";
+        let (rest, fm) = parse_front_matter(input).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            fm,
+            FrontMatter::Raw {
+                raw: input.to_string()
             }
+        );
+    }
+
+    #[test]
+    fn test_front_matter_tags() {
+        let input = indoc! {"
+            ---
+            tags:
+              - area/topic
+              - '#rust'
+              - 2024
+            aliases: [Ownership rules, rust]
+            anki_sync:
+              tags: [book, rust]
+              front_matter_tags: true
+            ---
+        "};
+        let (_, fm) = parse_front_matter(input).unwrap();
+        match fm {
+            FrontMatter::AnkiSync { settings, .. } => assert_eq!(
+                settings.tags,
+                vec!["book", "rust", "area::topic", "2024", "Ownership_rules"]
+            ),
+            _ => panic!("Expected AnkiSync"),
+        }
+    }
+
+    #[test]
+    fn test_front_matter_tags_as_strings() {
+        let input = indoc! {"
+            ---
+            tags: area/topic, rust  draft
+            aliases: Ownership rules
+            anki_sync:
+              front_matter_tags: true
+            ---
+        "};
+        let (_, fm) = parse_front_matter(input).unwrap();
+        match fm {
+            FrontMatter::AnkiSync { settings, .. } => assert_eq!(
+                settings.tags,
+                vec!["area::topic", "rust", "draft", "Ownership_rules"]
+            ),
+            _ => panic!("Expected AnkiSync"),
+        }
+    }
+
+    #[test]
+    fn test_front_matter_tags_opt_in() {
+        let input = indoc! {"
+            ---
+            tags: [rust]
+            anki_sync:
+              deck: Rust
+            ---
+        "};
+        let (_, fm) = parse_front_matter(input).unwrap();
+        match fm {
+            FrontMatter::AnkiSync { settings, .. } => assert!(settings.tags.is_empty()),
             _ => panic!("Expected AnkiSync"),
         }
     }
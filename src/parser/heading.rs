@@ -0,0 +1,81 @@
+use super::code_fence::parse_fenced_code;
+use super::flashcard::parse_header_hashes;
+use nom::{
+    IResult, Parser,
+    branch::alt,
+    character::complete::{line_ending, not_line_ending, space0, space1},
+    combinator::{eof, not, recognize, rest},
+    multi::many0,
+};
+
+/// An ATX heading, returning its level and its text without a closing sequence of `#`.
+pub fn parse_heading(input: &str) -> IResult<&str, (u8, &str)> {
+    let (input, _) = space0.parse(input)?;
+    let (input, level) = parse_header_hashes(input)?;
+    let (input, text) = alt((
+        recognize((space1, not_line_ending)),
+        recognize(line_ending),
+        eof,
+    ))
+    .parse(input)?;
+    let (input, _) = alt((recognize(line_ending), eof)).parse(input)?;
+    let text = text.trim();
+    let text = match text.trim_end_matches('#') {
+        closed if closed.is_empty() || closed.ends_with(' ') => closed.trim_end(),
+        _ => text,
+    };
+    Ok((input, (level, text)))
+}
+
+fn other_line(input: &str) -> IResult<&str, Option<(u8, &str)>> {
+    let (input, _) = not(eof).parse(input)?;
+    let (input, _) = alt((recognize((not_line_ending, line_ending)), rest)).parse(input)?;
+    Ok((input, None))
+}
+
+/// The headings of a passthrough block, in order. Lines inside fenced code are skipped.
+pub fn parse_headings(input: &str) -> IResult<&str, Vec<(u8, &str)>> {
+    let (input, lines) = many0(alt((
+        |i| parse_fenced_code(i).map(|(r, _)| (r, None)),
+        |i| parse_heading(i).map(|(r, h)| (r, Some(h))),
+        other_line,
+    )))
+    .parse(input)?;
+    Ok((input, lines.into_iter().flatten().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_heading() {
+        assert_eq!(
+            parse_heading("## Ownership\nrest"),
+            Ok(("rest", (2, "Ownership")))
+        );
+        assert_eq!(parse_heading("# Rust #\n"), Ok(("", (1, "Rust"))));
+        assert_eq!(parse_heading("# C#"), Ok(("", (1, "C#"))));
+        assert!(parse_heading("#hashtag\n").is_err());
+        assert!(parse_heading("####### Seven\n").is_err());
+    }
+
+    #[test]
+    fn test_headings_outside_code() {
+        let input = indoc! {"
+            # Rust
+
+            Some text.
+
+            ```sh
+            # a comment
+            ```
+            ## Ownership
+        "};
+        assert_eq!(
+            parse_headings(input),
+            Ok(("", vec![(1, "Rust"), (2, "Ownership")]))
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use nom::{
+    IResult, Parser,
+    bytes::complete::{tag, take_till, take_till1},
+    character::complete::space1,
+    combinator::{opt, recognize},
+    sequence::{delimited, preceded},
+};
+
+/// An inline `![alt](path "title")` image, returning the text in front of its path and
+/// the path.
+pub fn parse_image(input: &str) -> IResult<&str, (&str, &str)> {
+    let (input, opening) = recognize(delimited(
+        tag("!["),
+        take_till(|c| c == ']' || c == '\n'),
+        tag("]("),
+    ))
+    .parse(input)?;
+    let (input, path) = take_till1(|c: char| c == ')' || c.is_whitespace()).parse(input)?;
+    let (input, _) = opt(preceded(
+        space1,
+        delimited(tag("\""), take_till(|c| c == '"' || c == '\n'), tag("\"")),
+    ))
+    .parse(input)?;
+    let (input, _) = tag(")").parse(input)?;
+    Ok((input, (opening, path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image() {
+        assert_eq!(
+            parse_image("![diagram](img/foo.png) rest"),
+            Ok((" rest", ("![diagram](", "img/foo.png")))
+        );
+        assert_eq!(
+            parse_image("![](foo.png \"A title\")"),
+            Ok(("", ("![](", "foo.png")))
+        );
+    }
+
+    #[test]
+    fn test_link_rejected() {
+        assert!(parse_image("[link](foo.png)").is_err());
+        assert!(parse_image("![broken](foo.png").is_err());
+        assert!(parse_image("![](\nfoo.png)").is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use super::cloze::parse_cloze;
+use super::code_fence::parse_fenced_code;
 use super::flashcard::parse_flashcard;
 use super::flashcard_metadata::parse_flashcard_metadata;
 use crate::types::PassthroughBlock;
@@ -12,12 +14,13 @@ use nom::{
 fn non_special_line(input: &str) -> IResult<&str, &str> {
     let (input, _) = not(eof).parse(input)?;
     let (input, _) = not(parse_flashcard).parse(input)?;
+    let (input, _) = not(parse_cloze).parse(input)?;
     let (input, _) = not(parse_flashcard_metadata).parse(input)?;
     alt((recognize((not_line_ending, line_ending)), rest)).parse(input)
 }
 
 pub fn parse_passthrough_block(input: &str) -> IResult<&str, PassthroughBlock> {
-    let (input, raw) = recognize(many1(non_special_line)).parse(input)?;
+    let (input, raw) = recognize(many1(alt((parse_fenced_code, non_special_line)))).parse(input)?;
     Ok((
         input,
         PassthroughBlock {
@@ -126,6 +129,17 @@ mod tests {
         assert!(parse_passthrough_block(input).is_err());
     }
 
+    #[test]
+    fn test_stops_before_cloze_header() {
+        let input = indoc! {"
+            Regular content.
+            ## C: ==Ferris== is the Rust mascot.
+        "};
+        let (rest, block) = parse_passthrough_block(input).unwrap();
+        assert_eq!(block.raw, "Regular content.\n");
+        assert_eq!(rest, "## C: ==Ferris== is the Rust mascot.\n");
+    }
+
     #[test]
     fn test_regular_html_comment_consumed() {
         let input = indoc! {"
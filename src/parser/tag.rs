@@ -0,0 +1,165 @@
+use nom::{IResult, Parser, branch::alt, bytes::complete::tag, combinator::map};
+use std::borrow::Cow;
+
+/// Characters of an unquoted tag: letters and digits of any script, and the `:`, `-`,
+/// `_`, `/` and `.` used by hierarchical tags such as `lang::rust`.
+pub fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, ':' | '-' | '_' | '/' | '.')
+}
+
+/// Tag characters up to, but not including, a `-->` that closes the comment.
+fn parse_unquoted(input: &str) -> IResult<&str, &str> {
+    let end = input
+        .char_indices()
+        .find(|(i, c)| !is_tag_char(*c) || input[*i..].starts_with("-->"))
+        .map_or(input.len(), |(i, _)| i);
+    if end == 0 {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TakeWhile1,
+        )));
+    }
+    Ok((&input[end..], &input[..end]))
+}
+
+/// A string in double quotes. A backslash only escapes a `"`, another backslash or a
+/// line break written as `\n` or `\r`, so that values written without escapes, such as
+/// `"C:\Notes"`, keep their backslashes.
+fn parse_quoted(input: &str) -> IResult<&str, String> {
+    let (mut rest, _) = tag("\"").parse(input)?;
+    let mut value = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Ok((chars.as_str(), value)),
+            '\n' | '\r' => break,
+            '\\' if chars.as_str().starts_with(['"', '\\', 'n', 'r']) => {
+                value.extend(chars.next().map(|escaped| match escaped {
+                    'n' => '\n',
+                    'r' => '\r',
+                    escaped => escaped,
+                }));
+            }
+            c => value.push(c),
+        }
+        rest = chars.as_str();
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        rest,
+        nom::error::ErrorKind::Char,
+    )))
+}
+
+/// A tag, deck or note type name, either unquoted or in double quotes.
+pub fn parse_tag(input: &str) -> IResult<&str, Cow<'_, str>> {
+    alt((
+        map(parse_unquoted, Cow::Borrowed),
+        map(parse_quoted, Cow::Owned),
+    ))
+    .parse(input)
+}
+
+/// Writes `value` so that `parse_tag` reads it back unchanged: unquoted when it only
+/// has tag characters, otherwise quoted with `\` and `"` escaped. A comment cannot hold
+/// line breaks, so they are written as `\n` and `\r`.
+pub fn format_tag(value: &str) -> String {
+    if parse_unquoted(value) == Ok(("", value)) {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\\' | '"' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Values for property tests of `format_tag`, with the characters it has to escape
+/// mixed into arbitrary text.
+#[cfg(test)]
+pub(crate) fn any_value() -> impl proptest::strategy::Strategy<Value = String> {
+    use proptest::prelude::*;
+    let special = prop::sample::select(vec!['\n', '\r', '"', '\\']);
+    prop::collection::vec(prop_oneof![any::<char>(), special], 0..12).prop_map(String::from_iter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_unquoted_tags() {
+        for tag in [
+            "rust",
+            "lang::rust",
+            "spaced-repetition",
+            "ch_3.2",
+            "a/b",
+            "ownership::Élő",
+        ] {
+            assert_eq!(parse_tag(tag), Ok(("", Cow::Borrowed(tag))));
+        }
+        assert_eq!(parse_tag("rust, go"), Ok((", go", Cow::Borrowed("rust"))));
+        assert_eq!(parse_tag("rust]"), Ok(("]", Cow::Borrowed("rust"))));
+        assert_eq!(parse_tag("Rust-->"), Ok(("-->", Cow::Borrowed("Rust"))));
+    }
+
+    #[test]
+    fn test_quoted_tag() {
+        assert_eq!(
+            parse_tag("\"tag, two\"]"),
+            Ok(("]", Cow::Borrowed("tag, two")))
+        );
+        assert_eq!(
+            parse_tag(r#""say \"hi\" \\ -->""#),
+            Ok(("", Cow::Borrowed("say \"hi\" \\ -->")))
+        );
+        assert!(parse_tag("\"unterminated\n\"").is_err());
+    }
+
+    #[test]
+    fn test_formatted_tags() {
+        assert_eq!(format_tag("lang::rust"), "lang::rust");
+        assert_eq!(
+            format_tag("Basic (and reversed card)"),
+            "\"Basic (and reversed card)\""
+        );
+        assert_eq!(format_tag("a-->b"), r#""a-->b""#);
+        assert_eq!(format_tag("two\r\nlines"), r#""two\r\nlines""#);
+        assert_eq!(
+            parse_tag(r#""two\nlines""#),
+            Ok(("", Cow::Borrowed("two\nlines")))
+        );
+        assert_eq!(format_tag(""), "\"\"");
+    }
+
+    #[test]
+    fn test_backslash_without_escape_kept() {
+        let legacy = r#""C:\Notes\old""#;
+        let value = parse_tag(legacy).unwrap().1;
+        assert_eq!(value, r"C:\Notes\old");
+        assert_eq!(format_tag(&value), r#""C:\\Notes\\old""#);
+        assert_eq!(parse_tag(&format_tag(&value)), Ok(("", value)));
+    }
+
+    proptest! {
+        #[test]
+        fn test_formatted_tag_parses_back(
+            value in any_value(),
+            rest in prop::sample::select(vec!["", " -->", ", next", "]"]),
+        ) {
+            let formatted = format_tag(&value) + rest;
+            prop_assert_eq!(parse_tag(&formatted), Ok((rest, Cow::Owned(value))));
+        }
+    }
+}
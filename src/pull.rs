@@ -0,0 +1,348 @@
+use crate::anki::{AnkiClient, Note, NoteInfo};
+use crate::anki_sync::{
+    Action, BlockSyncError, BlockSyncPlan, DocumentSyncPlan, SyncOptions, content_hash,
+    field_target, record_sync_state,
+};
+use crate::media::restore_image_paths;
+use crate::parser::field_section::split_field_sections;
+use crate::parser::flashcard::back_range;
+use crate::render::math::restore_math;
+use crate::render::{unrender_block, unrender_inline};
+use crate::types::{Block, FlashCard, MarkdownDocument, SyncSettings};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::AddAssign;
+use std::path::Path;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PullSummary {
+    pub pulled: u32,
+    pub failed: u32,
+}
+
+impl AddAssign for PullSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.pulled += other.pulled;
+        self.failed += other.failed;
+    }
+}
+
+impl fmt::Display for PullSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pulled: {}, Failed: {}", self.pulled, self.failed)
+    }
+}
+
+/// Outcome of a pull. Blocks that failed keep their original text in `document`.
+#[derive(Debug)]
+pub struct PullReport {
+    pub document: MarkdownDocument,
+    pub summary: PullSummary,
+    pub errors: Vec<BlockSyncError>,
+}
+
+/// Rewrites the front and the answer of a card, keeping its header, the blank lines
+/// around the answer and any sections after it named after a field `is_field` accepts.
+fn rewrite_flashcard(
+    card: &FlashCard,
+    front: &str,
+    body: &str,
+    is_field: impl Fn(&str) -> bool,
+) -> FlashCard {
+    let line_end = card.raw.find('\n').unwrap_or(card.raw.len());
+    let header = card.raw[..line_end].trim_end_matches('\r');
+    let prefix = header.strip_suffix(card.front.as_str()).unwrap_or(header);
+
+    let (_, sections) = split_field_sections(&card.back, is_field);
+    let mut back = body.to_string();
+    if !sections.is_empty() {
+        back.push('\n');
+        back.push_str(sections);
+    }
+    if !card.back.ends_with('\n') {
+        back.truncate(back.trim_end_matches('\n').len());
+    }
+
+    let rest = match back_range(&card.raw) {
+        Some(range) => format!(
+            "{}{}{}",
+            &card.raw[header.len()..range.start],
+            back,
+            &card.raw[range.end..]
+        ),
+        None => card.raw[header.len()..].to_string(),
+    };
+
+    FlashCard {
+        raw: format!("{}{}{}", prefix, front, rest),
+        front: front.to_string(),
+        back,
+    }
+}
+
+/// The field of `info` a planned field went into, and its content in Anki.
+fn anki_field<'a>(info: &'a NoteInfo, planned: &str) -> Result<&'a str, String> {
+    let names = info.field_names();
+    let name = field_target(planned, &names).ok_or_else(|| {
+        format!(
+            "Note type '{}' has no field for '{}'",
+            info.model_name, planned
+        )
+    })?;
+    Ok(info.fields[name].value.as_str())
+}
+
+impl BlockSyncPlan {
+    /// The block with the front and answer of its card replaced by the ones in Anki,
+    /// and the sync state of the pulled note recorded. Returns `None` when Anki holds
+    /// what the Markdown would sync. Only question and answer cards can be pulled, so
+    /// any other card that changed in Anki is an error.
+    pub(crate) fn pulled_block(
+        &self,
+        info: &NoteInfo,
+        settings: &SyncSettings,
+        media_dir: &Path,
+        options: &SyncOptions,
+    ) -> Result<Option<Block>, String> {
+        let mode = settings.render.unwrap_or(options.render);
+        let Action::UpdateNote(note) = &self.action else {
+            return Ok(None);
+        };
+        if info.note_id == 0 {
+            return Err(format!(
+                "Note {} was not found in Anki",
+                note.id.unwrap_or_default()
+            ));
+        }
+        let Block::FlashCardWithMeta { flashcard, .. } = &self.block else {
+            let names = info.field_names();
+            for (name, content) in self.model_fields(&names, mode, media_dir) {
+                if anki_field(info, &name)? != content {
+                    return Err("Cannot pull this card type; edit it in the Markdown".to_string());
+                }
+            }
+            return Ok(None);
+        };
+
+        let names = info.field_names();
+        let is_field = |name: &str| names.iter().any(|field| field == name);
+        let fields = self.model_fields(&names, mode, media_dir);
+        let planned = |name: &str| fields.get(name).map_or("", String::as_str);
+        let anki_front = anki_field(info, "Front")?;
+        let anki_back = anki_field(info, "Back")?;
+        if anki_front == planned("Front") && anki_back == planned("Back") {
+            return Ok(None);
+        }
+
+        let front = if anki_front == planned("Front") {
+            flashcard.front.clone()
+        } else {
+            restore_image_paths(
+                &restore_math(&unrender_inline(anki_front, mode)),
+                &self.media,
+            )
+        };
+        let body = if anki_back == planned("Back") {
+            split_field_sections(&flashcard.back, is_field)
+                .0
+                .to_string()
+        } else {
+            restore_image_paths(&restore_math(&unrender_block(anki_back, mode)), &self.media)
+        };
+        if front.trim().is_empty() || body.trim().is_empty() {
+            return Err("Cannot pull a card with an empty front or answer".to_string());
+        }
+
+        let mut block = self.block.clone();
+        if let Block::FlashCardWithMeta { flashcard, .. } = &mut block {
+            *flashcard = rewrite_flashcard(flashcard, &front, &body, is_field);
+        }
+        // Pulling leaves the tags alone, including those that come from around the block.
+        let pulled = BlockSyncPlan::from_block(block.clone(), settings, media_dir, options);
+        let hash = pulled.action.note().map(|pulled| {
+            content_hash(&Note {
+                tags: note.tags.clone(),
+                ..pulled.clone()
+            })
+        });
+        record_sync_state(&mut block, hash, Some(info.modified));
+        Ok(Some(block))
+    }
+}
+
+impl DocumentSyncPlan {
+    /// Replaces the front and answer of every synced card with its fields in Anki, so
+    /// that edits made while reviewing are kept. Passthrough blocks are left as they are,
+    /// and cloze cards that changed in Anki are reported as failed.
+    pub fn pull(self, client: &impl AnkiClient) -> Result<PullReport, String> {
+        let start_lines = self.block_start_lines();
+        let ids: Vec<u64> = self
+            .block_plans
+            .iter()
+            .filter_map(|block_plan| match &block_plan.action {
+                Action::UpdateNote(Note { id: Some(id), .. }) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        let infos: BTreeMap<u64, NoteInfo> = if ids.is_empty() {
+            BTreeMap::new()
+        } else {
+            let infos = client.notes_info(&ids).map_err(|e| e.to_string())?;
+            ids.into_iter().zip(infos).collect()
+        };
+
+        let mut blocks = Vec::with_capacity(self.block_plans.len());
+        let mut summary = PullSummary::default();
+        let mut errors = Vec::new();
+        for (block_plan, line) in self.block_plans.into_iter().zip(start_lines) {
+            let info = block_plan
+                .action
+                .note()
+                .and_then(|note| note.id)
+                .and_then(|id| infos.get(&id));
+            let Some(info) = info else {
+                blocks.push(block_plan.block);
+                continue;
+            };
+            let pulled =
+                block_plan.pulled_block(info, &self.settings, &self.media_dir, &self.options);
+            match pulled {
+                Ok(Some(block)) => {
+                    summary.pulled += 1;
+                    blocks.push(block);
+                }
+                Ok(None) => blocks.push(block_plan.block),
+                Err(message) => {
+                    summary.failed += 1;
+                    errors.push(BlockSyncError {
+                        line,
+                        front: block_plan.front().unwrap_or_default().to_string(),
+                        message,
+                    });
+                    blocks.push(block_plan.block);
+                }
+            }
+        }
+
+        Ok(PullReport {
+            document: MarkdownDocument {
+                front_matter: self.front_matter,
+                blocks,
+            },
+            summary,
+            errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::NoteFields;
+    use crate::anki::fake::FakeAnki;
+    use crate::anki_sync::SyncOptions;
+    use crate::parser::document::parse_document;
+    use indoc::indoc;
+
+    fn pull(input: &str, anki: &FakeAnki) -> PullReport {
+        let (_, doc) = parse_document(input).unwrap();
+        DocumentSyncPlan::from_document(doc, Path::new(""), Path::new(""), &SyncOptions::default())
+            .pull(anki)
+            .unwrap()
+    }
+
+    fn anki_note(anki: &FakeAnki, id: u64, front: &str, back: &str) {
+        anki.insert_note(Note {
+            id: Some(id),
+            deck_name: "Default".to_string(),
+            model_name: "Basic".to_string(),
+            fields: NoteFields::from([
+                ("Front".to_string(), front.to_string()),
+                ("Back".to_string(), back.to_string()),
+            ]),
+            tags: vec![],
+            options: None,
+        });
+    }
+
+    #[test]
+    fn test_edits_pulled_in_place() {
+        let input = indoc! {"
+            # Rust
+
+            Some notes that are not cards.
+
+            <!-- anki_id: 1 -->
+            ## Q: What is `Rc`?
+
+            A reference-counted pointr.
+
+            ### Hint:
+            Counting.
+
+            <!-- anki_id: 2 -->
+            ## Q: Unchanged?
+            Yes.
+        "};
+        let anki = FakeAnki::new();
+        anki.add_model("Basic", &["Front", "Back", "Hint"]);
+        anki_note(
+            &anki,
+            1,
+            "What is <code>Rc</code>?",
+            "<p>A <b>reference-counted</b> pointer.</p>",
+        );
+        anki_note(&anki, 2, "Unchanged?", "<p>Yes.</p>\n");
+
+        let report = pull(input, &anki);
+        assert_eq!(
+            report.summary,
+            PullSummary {
+                pulled: 1,
+                failed: 0
+            }
+        );
+        assert_eq!(
+            report.document.raw(),
+            indoc! {"
+                # Rust
+
+                Some notes that are not cards.
+
+                <!-- anki_id: 1, anki_hash: ea6d3def385997fe, anki_mod: 1700000001 -->
+                ## Q: What is `Rc`?
+
+                A **reference-counted** pointer.
+
+                ### Hint:
+                Counting.
+
+                <!-- anki_id: 2 -->
+                ## Q: Unchanged?
+                Yes.
+            "}
+        );
+    }
+
+    #[test]
+    fn test_front_pulled() {
+        let input = "<!-- anki_id: 1 -->\n## Q: Waht is Rust?\nA language.";
+        let anki = FakeAnki::new();
+        anki_note(&anki, 1, "What is <i>Rust</i>?", "<p>A language.</p>\n");
+
+        let report = pull(input, &anki);
+        assert_eq!(
+            report.document.raw(),
+            "<!-- anki_id: 1, anki_hash: 54a8acc4bfc894b8, anki_mod: 1700000001 -->\n## Q: What is *Rust*?\nA language."
+        );
+    }
+
+    #[test]
+    fn test_missing_note_fails_block() {
+        let input = "<!-- anki_id: 9 -->\n## Q: Gone?\nYes.\n";
+        let report = pull(input, &FakeAnki::new());
+        assert_eq!(report.summary.failed, 1);
+        assert_eq!(report.errors[0].message, "Note 9 was not found in Anki");
+        assert_eq!(report.document.raw(), input);
+    }
+}
@@ -0,0 +1,136 @@
+use pulldown_cmark::{Options, Parser, html};
+use serde::Deserialize;
+
+pub mod markdown;
+pub mod math;
+
+/// How flashcard text is turned into Anki field content.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    /// CommonMark with the GFM extensions (tables, strikethrough, task lists) as HTML.
+    #[default]
+    Html,
+    /// The Markdown source, unchanged.
+    Raw,
+}
+
+fn markdown_options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_GFM
+}
+
+fn render_html(markdown: &str) -> String {
+    let mut output = String::new();
+    html::push_html(&mut output, Parser::new_ext(markdown, markdown_options()));
+    output
+}
+
+/// Renders a block of Markdown, such as the back of a card.
+pub fn render_block(markdown: &str, mode: RenderMode) -> String {
+    match mode {
+        RenderMode::Html => render_html(markdown),
+        RenderMode::Raw => markdown.to_string(),
+    }
+}
+
+/// Renders a single line of Markdown, such as the front of a card. The paragraph the
+/// renderer wraps the line in is dropped so the field holds inline content only.
+pub fn render_inline(markdown: &str, mode: RenderMode) -> String {
+    let rendered = render_block(markdown, mode);
+    match mode {
+        RenderMode::Html => rendered
+            .strip_prefix("<p>")
+            .and_then(|s| s.strip_suffix("</p>\n"))
+            .filter(|s| !s.contains("<p>"))
+            .map(String::from)
+            .unwrap_or(rendered),
+        RenderMode::Raw => rendered,
+    }
+}
+
+/// Turns the content of a field rendered with `render_block` back into Markdown.
+pub fn unrender_block(field: &str, mode: RenderMode) -> String {
+    match mode {
+        RenderMode::Html => markdown::html_to_markdown(field),
+        RenderMode::Raw => field.to_string(),
+    }
+}
+
+/// Turns the content of a field rendered with `render_inline` back into a single line
+/// of Markdown.
+pub fn unrender_inline(field: &str, mode: RenderMode) -> String {
+    match mode {
+        RenderMode::Html => markdown::html_to_inline_markdown(field),
+        RenderMode::Raw => field.lines().collect::<Vec<_>>().join(" "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_inline_emphasis_and_code() {
+        assert_eq!(
+            render_inline("What does `Box<T>` **own**?", RenderMode::Html),
+            "What does <code>Box&lt;T&gt;</code> <strong>own</strong>?"
+        );
+    }
+
+    #[test]
+    fn test_inline_block_content_kept() {
+        assert_eq!(
+            render_inline("- item", RenderMode::Html),
+            "<ul>\n<li>item</li>\n</ul>\n"
+        );
+    }
+
+    #[test]
+    fn test_block_list_and_fenced_code() {
+        let markdown = indoc! {"
+            - one
+            - ~~two~~
+
+            ```rust
+            let x = 1;
+            ```
+        "};
+        assert_eq!(
+            render_block(markdown, RenderMode::Html),
+            indoc! {r#"
+                <ul>
+                <li>one</li>
+                <li><del>two</del></li>
+                </ul>
+                <pre><code class="language-rust">let x = 1;
+                </code></pre>
+            "#}
+        );
+    }
+
+    #[test]
+    fn test_block_table_and_task_list() {
+        let markdown = indoc! {"
+            | a | b |
+            |---|---|
+            | 1 | 2 |
+
+            - [x] done
+        "};
+        let html = render_block(markdown, RenderMode::Html);
+        assert!(html.contains("<table>"));
+        assert!(html.contains("<td>1</td>"));
+        assert!(html.contains(r#"<input disabled="" type="checkbox" checked=""/>"#));
+    }
+
+    #[test]
+    fn test_raw_mode_keeps_markdown() {
+        let markdown = "**bold** and `code`\n";
+        assert_eq!(render_block(markdown, RenderMode::Raw), markdown);
+        assert_eq!(render_inline("**bold**", RenderMode::Raw), "**bold**");
+    }
+}
@@ -0,0 +1,402 @@
+//! Converts the HTML of Anki fields back to Markdown. It covers the markup the renderer
+//! produces and the Anki editor adds (`div`, `br`, `b`, `i`, `span`); unknown tags are
+//! dropped and their content kept.
+
+struct Element {
+    name: String,
+    href: Option<String>,
+    content: String,
+    /// Number of the next item of an ordered list.
+    next_item: usize,
+}
+
+impl Element {
+    fn new(name: &str, href: Option<String>) -> Self {
+        Element {
+            name: name.to_string(),
+            href,
+            content: String::new(),
+            next_item: 1,
+        }
+    }
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!("{}=", name))? + name.len() + 1;
+    let value = &tag[start..];
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let end = value[1..].find(quote)? + 1;
+    Some(decode_entities(&value[1..end]))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .or_else(|| entity.strip_prefix('#').and_then(|n| n.parse().ok()))
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, end + 1))
+        });
+        match decoded {
+            Some((c, len)) => {
+                result.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Collapses runs of whitespace like HTML does outside of `pre`.
+fn collapse_whitespace(text: &str, previous: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut in_space = previous.is_empty() || previous.ends_with(char::is_whitespace);
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !in_space {
+                result.push(' ');
+            }
+            in_space = true;
+        } else {
+            result.push(c);
+            in_space = false;
+        }
+    }
+    result
+}
+
+fn block(content: &str) -> String {
+    format!("\n\n{}\n\n", content.trim())
+}
+
+fn prefix_lines(content: &str, first: &str, other: &str) -> String {
+    content
+        .trim()
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let prefix = if i == 0 { first } else { other };
+            if line.is_empty() {
+                prefix.trim_end().to_string()
+            } else {
+                format!("{}{}", prefix, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn close(element: Element, parent: &mut Element) -> String {
+    let content = element.content;
+    match element.name.as_str() {
+        "p" | "div" | "ul" | "ol" => block(&content),
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let level = element.name[1..].parse().unwrap_or(1);
+            block(&format!("{} {}", "#".repeat(level), content.trim()))
+        }
+        "blockquote" => block(&prefix_lines(&content, "> ", "> ")),
+        "pre" => format!("\n\n```\n{}\n```\n\n", content.trim_end_matches('\n')),
+        "li" => {
+            let marker = if parent.name == "ol" {
+                parent.next_item += 1;
+                format!("{}. ", parent.next_item - 1)
+            } else {
+                "- ".to_string()
+            };
+            let indent = " ".repeat(marker.len());
+            let separator = if parent.content.is_empty() || parent.content.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            format!(
+                "{}{}\n",
+                separator,
+                prefix_lines(&content, &marker, &indent)
+            )
+        }
+        "strong" | "b" if !content.trim().is_empty() => format!("**{}**", content),
+        "em" | "i" if !content.trim().is_empty() => format!("*{}*", content),
+        "del" | "s" if !content.trim().is_empty() => format!("~~{}~~", content),
+        "code" if parent.name != "pre" => format!("`{}`", content),
+        "a" => match element.href {
+            Some(href) => format!("[{}]({})", content, href),
+            None => content,
+        },
+        _ => content,
+    }
+}
+
+fn in_pre(stack: &[Element]) -> bool {
+    stack.iter().any(|element| element.name == "pre")
+}
+
+fn in_code(stack: &[Element]) -> bool {
+    stack.iter().any(|element| element.name == "code")
+}
+
+/// Escapes what Markdown would read as markup in `text`, which starts a line when
+/// `line_start` is set. MathJax is kept as it is for `restore_math`, and `_` inside a
+/// word is left alone as it never emphasises.
+fn escape_text(text: &str, line_start: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    let indent = text.len() - text.trim_start_matches(' ').len();
+    let (spaces, text) = text.split_at(indent);
+    result.push_str(spaces);
+    if line_start {
+        let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let marker = text[digits..].chars().next();
+        let opens_block = match marker {
+            Some('.' | ')') => digits > 0,
+            Some('#' | '>' | '-' | '+') => digits == 0,
+            _ => false,
+        };
+        if opens_block {
+            result.push_str(&text[..digits]);
+            result.push('\\');
+            result.extend(marker);
+            return result + &escape_text(&text[digits + 1..], false);
+        }
+    }
+
+    let mut previous = None;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let math_end = match rest.get(..2) {
+            Some("\\(") => rest[2..].find("\\)"),
+            Some("\\[") => rest[2..].find("\\]"),
+            _ => None,
+        };
+        if let Some(end) = math_end {
+            result.push_str(&rest[..end + 4]);
+            rest = &rest[end + 4..];
+            previous = Some(')');
+            continue;
+        }
+        rest = &rest[c.len_utf8()..];
+        let next = rest.chars().next();
+        let escaped = match c {
+            '\\' | '*' | '`' | '[' | ']' | '<' | '~' => true,
+            '_' => {
+                !(previous.is_some_and(char::is_alphanumeric)
+                    && next.is_some_and(char::is_alphanumeric))
+            }
+            '&' => next.is_some_and(|next| next.is_ascii_alphanumeric() || next == '#'),
+            _ => false,
+        };
+        if escaped {
+            result.push('\\');
+        }
+        result.push(c);
+        previous = Some(c);
+    }
+    result
+}
+
+/// Converts a field to Markdown blocks, ending with a newline unless it is empty.
+pub fn html_to_markdown(html: &str) -> String {
+    let mut stack = vec![Element::new("", None)];
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        let (text, tag) = match rest.find('<') {
+            Some(0) => match rest.find('>') {
+                Some(end) => {
+                    let tag = &rest[1..end];
+                    rest = &rest[end + 1..];
+                    ("", Some(tag))
+                }
+                None => (std::mem::take(&mut rest), None),
+            },
+            Some(start) => {
+                let text = &rest[..start];
+                rest = &rest[start..];
+                (text, None)
+            }
+            None => (std::mem::take(&mut rest), None),
+        };
+
+        // Whitespace between list items and quoted blocks is only source formatting.
+        let top_name = stack.last().map_or("", |element| element.name.as_str());
+        let skipped = matches!(top_name, "ul" | "ol" | "blockquote") && text.trim().is_empty();
+        if !text.is_empty() && !skipped {
+            let decoded = decode_entities(text);
+            let text = if in_pre(&stack) {
+                decoded
+            } else {
+                let top = stack.last().map_or("", |element| element.content.as_str());
+                let collapsed = collapse_whitespace(&decoded, top);
+                if in_code(&stack) {
+                    collapsed
+                } else {
+                    escape_text(&collapsed, top.is_empty() || top.ends_with('\n'))
+                }
+            };
+            stack.last_mut().unwrap().content.push_str(&text);
+        }
+
+        let Some(tag) = tag else { continue };
+        if tag.starts_with('!') {
+            continue;
+        }
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+
+        if tag.starts_with('/') {
+            if let Some(position) = stack.iter().rposition(|element| element.name == name)
+                && position > 0
+            {
+                while stack.len() > position {
+                    let element = stack.pop().unwrap();
+                    let parent = stack.last_mut().unwrap();
+                    let markdown = close(element, parent);
+                    parent.content.push_str(&markdown);
+                }
+            }
+            continue;
+        }
+
+        let top = &mut stack.last_mut().unwrap().content;
+        match name.as_str() {
+            "br" => top.push('\n'),
+            "hr" => top.push_str("\n\n---\n\n"),
+            "img" => {
+                let src = attribute(tag, "src").unwrap_or_default();
+                let alt = attribute(tag, "alt").unwrap_or_default();
+                top.push_str(&format!("![{}]({})", alt, src));
+            }
+            _ if tag.ends_with('/') => {}
+            _ => stack.push(Element::new(&name, attribute(tag, "href"))),
+        }
+    }
+
+    while stack.len() > 1 {
+        let element = stack.pop().unwrap();
+        let parent = stack.last_mut().unwrap();
+        let markdown = close(element, parent);
+        parent.content.push_str(&markdown);
+    }
+
+    let markdown = stack.pop().unwrap().content;
+    let mut lines: Vec<&str> = Vec::new();
+    for line in markdown.lines().map(str::trim_end) {
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", lines.join("\n"))
+    }
+}
+
+/// Converts a field to a single line of Markdown, joining its lines with spaces.
+pub fn html_to_inline_markdown(html: &str) -> String {
+    html_to_markdown(html)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{RenderMode, render_block, render_inline};
+    use indoc::indoc;
+
+    #[test]
+    fn test_rendered_markdown_round_trips() {
+        let markdown = indoc! {"
+            A **bold** and *emphasised* `code` [link](https://example.com).
+
+            - one
+            - two
+
+            1. first
+            2. second
+
+            > quoted
+
+            ```
+            let x = 1 < 2;
+            ```
+        "};
+        assert_eq!(
+            html_to_markdown(&render_block(markdown, RenderMode::Html)),
+            markdown
+        );
+    }
+
+    #[test]
+    fn test_inline_round_trips() {
+        let markdown = "What does `Box<T>` **own** & ~~lose~~?";
+        assert_eq!(
+            html_to_inline_markdown(&render_inline(markdown, RenderMode::Html)),
+            markdown
+        );
+    }
+
+    #[test]
+    fn test_anki_editor_markup() {
+        let html = "Fixed&nbsp;<b>typo</b><div>second line</div><div><br></div><div>third</div>";
+        assert_eq!(
+            html_to_markdown(html),
+            "Fixed **typo**\n\nsecond line\n\nthird\n"
+        );
+    }
+
+    #[test]
+    fn test_image_and_line_break() {
+        assert_eq!(
+            html_to_markdown("<img src=\"ferris.png\"><br>Ferris"),
+            "![](ferris.png)\nFerris\n"
+        );
+    }
+
+    #[test]
+    fn test_text_escaped() {
+        assert_eq!(
+            html_to_markdown("<div>&lt;div&gt; is *not* [markup] in snake_case _here_</div>"),
+            "\\<div> is \\*not\\* \\[markup\\] in snake_case \\_here\\_\n"
+        );
+        assert_eq!(
+            html_to_markdown("<div># Not a heading</div><div>1. Not a list</div>"),
+            "\\# Not a heading\n\n1\\. Not a list\n"
+        );
+        assert_eq!(
+            html_to_markdown("<code>a*b</code> &amp;amp; \\(a*b\\) C:\\dir"),
+            "`a*b` \\&amp; \\(a*b\\) C:\\\\dir\n"
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! Converts `$...$` and `$$...$$` math to the `\(...\)` and `\[...\]` delimiters of
+//! Anki's MathJax. Dollars follow the Pandoc rules so that amounts such as `$5 and $10`
+//! stay as they are: the opening `$` must be followed by a non-space character, and the
+//! closing one must follow a non-space character and not be followed by a digit.
+
+use super::RenderMode;
+use crate::parser::code_fence::{code_span_end, map_outside_fences};
+
+/// Converts the math of a card side outside of fenced code blocks. In `Html` mode the
+/// math is escaped so that the Markdown renderer leaves it alone.
+pub fn convert_math(markdown: &str, mode: RenderMode) -> String {
+    map_outside_fences(markdown, |text| convert_text(text, mode))
+}
+
+/// Escapes every ASCII punctuation character, which CommonMark then renders literally.
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn push_math(result: &mut String, math: &str, (open, close): (&str, &str), mode: RenderMode) {
+    let math = format!("{}{}{}", open, math, close);
+    match mode {
+        RenderMode::Html => result.push_str(&escape_markdown(&math)),
+        RenderMode::Raw => result.push_str(&math),
+    }
+}
+
+/// End of the `$$` display math starting at `rest`, which may span lines but not
+/// paragraphs.
+fn display_math(rest: &str) -> Option<&str> {
+    let end = rest[2..].find("$$")? + 2;
+    let math = &rest[2..end];
+    let lines: Vec<&str> = math.split('\n').collect();
+    let spans_paragraphs = lines.len() > 2
+        && lines[1..lines.len() - 1]
+            .iter()
+            .any(|line| line.trim().is_empty());
+    (!math.trim().is_empty() && !spans_paragraphs).then_some(math)
+}
+
+/// The `$` inline math starting at `rest`, which stays on one line.
+fn inline_math(rest: &str) -> Option<&str> {
+    let after = &rest[1..];
+    if after.starts_with(char::is_whitespace) || after.is_empty() {
+        return None;
+    }
+    let line = &after[..after.find('\n').unwrap_or(after.len())];
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '$' if i > 0 => {
+                let before = line[..i].chars().next_back();
+                let next = line[i + 1..].chars().next();
+                if before.is_some_and(|b| !b.is_whitespace())
+                    && !next.is_some_and(|n| n.is_ascii_digit())
+                {
+                    return Some(&line[..i]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn convert_text(text: &str, mode: RenderMode) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            // A code span runs up to the next backtick run of the same length.
+            '`' => {
+                let end = code_span_end(rest);
+                result.push_str(&rest[..end]);
+                rest = &rest[end..];
+            }
+            '\\' => {
+                let len = 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+                result.push_str(&rest[..len]);
+                rest = &rest[len..];
+            }
+            '$' if rest.starts_with("$$") => match display_math(rest) {
+                Some(math) => {
+                    push_math(&mut result, math, ("\\[", "\\]"), mode);
+                    rest = &rest[math.len() + 4..];
+                }
+                None => {
+                    result.push_str("$$");
+                    rest = &rest[2..];
+                }
+            },
+            '$' => match inline_math(rest) {
+                Some(math) => {
+                    push_math(&mut result, math, ("\\(", "\\)"), mode);
+                    rest = &rest[math.len() + 2..];
+                }
+                None => {
+                    result.push('$');
+                    rest = &rest[1..];
+                }
+            },
+            c => {
+                result.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    result
+}
+
+/// Whether the `$` starting `rest` opens math.
+fn opens_math(rest: &str) -> bool {
+    if rest.starts_with("$$") {
+        display_math(rest).is_some()
+    } else {
+        inline_math(rest).is_some()
+    }
+}
+
+fn restore_text(text: &str) -> String {
+    let mut restored = String::with_capacity(text.len());
+    let mut dollars = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '`' => code_span_end(rest),
+            '\\' => match rest.get(..2) {
+                Some("\\(" | "\\)") => {
+                    restored.push('$');
+                    rest = &rest[2..];
+                    continue;
+                }
+                Some("\\[" | "\\]") => {
+                    restored.push_str("$$");
+                    rest = &rest[2..];
+                    continue;
+                }
+                _ => 1 + rest[1..].chars().next().map_or(0, char::len_utf8),
+            },
+            '$' => {
+                dollars.push(restored.len());
+                1
+            }
+            c => c.len_utf8(),
+        };
+        restored.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    // Dollars that were text in Anki are escaped where they would now open math.
+    let mut result = String::with_capacity(restored.len());
+    let mut copied = 0;
+    for position in dollars {
+        if opens_math(&restored[position..]) {
+            result.push_str(&restored[copied..position]);
+            result.push('\\');
+            copied = position;
+        }
+    }
+    result.push_str(&restored[copied..]);
+    result
+}
+
+/// Turns MathJax delimiters in Markdown pulled from Anki back into dollars, leaving
+/// code alone.
+pub fn restore_math(markdown: &str) -> String {
+    map_outside_fences(markdown, restore_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::render_block;
+    use indoc::indoc;
+
+    fn raw(markdown: &str) -> String {
+        convert_math(markdown, RenderMode::Raw)
+    }
+
+    #[test]
+    fn test_inline_and_display_math() {
+        assert_eq!(
+            raw("Euler: $e^{i\\pi} + 1 = 0$."),
+            "Euler: \\(e^{i\\pi} + 1 = 0\\)."
+        );
+        assert_eq!(
+            raw("$$\n\\sum_{i=1}^n i\n$$\n"),
+            "\\[\n\\sum_{i=1}^n i\n\\]\n"
+        );
+    }
+
+    #[test]
+    fn test_currency_left_alone() {
+        for text in [
+            "It costs $5 and $10.",
+            "Between $5-$10 a month",
+            "Only $ 5",
+            "Escaped \\$x$ stays",
+            "A lone $",
+        ] {
+            assert_eq!(raw(text), text);
+        }
+    }
+
+    #[test]
+    fn test_code_left_alone() {
+        let markdown = indoc! {"
+            Run `echo $HOME$` or ``a ` $b$``, but $x$ is math.
+
+            ```sh
+            echo $PATH$
+            ```
+        "};
+        assert_eq!(raw(markdown), markdown.replace("but $x$", "but \\(x\\)"));
+    }
+
+    #[test]
+    fn test_math_survives_rendering() {
+        let html = render_block(
+            &convert_math("Let $a_1 * b_1 < c$.", RenderMode::Html),
+            RenderMode::Html,
+        );
+        assert_eq!(html, "<p>Let \\(a_1 * b_1 &lt; c\\).</p>\n");
+    }
+
+    #[test]
+    fn test_math_restored() {
+        assert_eq!(restore_math("\\(x\\) and \\[y\\]"), "$x$ and $$y$$");
+    }
+
+    #[test]
+    fn test_code_not_restored() {
+        let markdown = indoc! {"
+            Run `printf '\\(x\\)'` for \\(x\\).
+
+            ```tex
+            \\[ y \\]
+            ```
+        "};
+        assert_eq!(
+            restore_math(markdown),
+            markdown.replace("for \\(x\\)", "for $x$")
+        );
+    }
+
+    #[test]
+    fn test_literal_dollars_escaped_when_read_as_math() {
+        assert_eq!(restore_math("It costs $5 and $10."), "It costs $5 and $10.");
+        assert_eq!(restore_math("Escaped $x$ stays"), "Escaped \\$x$ stays");
+        assert_eq!(restore_math("From $5 to \\(x\\)"), "From \\$5 to $x$");
+        for text in ["Escaped \\$x$ stays", "From \\$5 to $x$"] {
+            assert_eq!(raw(&restore_math(&raw(text))), raw(text));
+        }
+    }
+}
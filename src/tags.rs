@@ -0,0 +1,166 @@
+//! Tags built from the structure of a document.
+
+use crate::anki_sync::root_relative_path;
+use crate::parser::heading::{parse_heading, parse_headings};
+use crate::types::{Block, FrontMatter, MarkdownDocument};
+use std::path::{Component, Path};
+
+/// Parent of the tags that name the file a note comes from.
+pub const SOURCE_TAG_PREFIX: &str = "markdown-anki-sync";
+
+/// Anki tags cannot contain spaces, so the words of a heading are joined with `_`.
+fn heading_component(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+fn card_level(block: &Block) -> Option<u8> {
+    let raw = match block {
+        Block::FlashCard(flashcard) | Block::FlashCardWithMeta { flashcard, .. } => &flashcard.raw,
+        Block::Cloze(cloze) | Block::ClozeWithMeta { cloze, .. } => &cloze.raw,
+        Block::Passthrough(_) => return None,
+    };
+    parse_heading(raw).ok().map(|(_, (level, _))| level)
+}
+
+/// The tag of every block when the front matter sets `heading_tags`: the headings a
+/// card is under joined with `::`, such as `Rust::Ownership` for a card under `# Rust`
+/// and `## Ownership`. Passthrough blocks and cards under no heading get `None`.
+pub fn heading_tags(doc: &MarkdownDocument) -> Vec<Option<String>> {
+    let enabled = matches!(
+        &doc.front_matter,
+        Some(FrontMatter::AnkiSync { settings, .. }) if settings.heading_tags
+    );
+    if !enabled {
+        return vec![None; doc.blocks.len()];
+    }
+
+    let mut headings: Vec<(u8, String)> = Vec::new();
+    doc.blocks
+        .iter()
+        .map(|block| {
+            if let Block::Passthrough(passthrough) = block {
+                let found = parse_headings(&passthrough.raw).map_or(vec![], |(_, found)| found);
+                for (level, text) in found {
+                    headings.retain(|(outer, _)| *outer < level);
+                    let component = heading_component(text);
+                    if !component.is_empty() {
+                        headings.push((level, component));
+                    }
+                }
+                return None;
+            }
+            let level = card_level(block)?;
+            headings.retain(|(outer, _)| *outer < level);
+            (!headings.is_empty()).then(|| {
+                headings
+                    .iter()
+                    .map(|(_, component)| component.as_str())
+                    .collect::<Vec<_>>()
+                    .join("::")
+            })
+        })
+        .collect()
+}
+
+/// The tag naming the file at the relative `path` on every note synced from it, such as
+/// `markdown-anki-sync::vault::rust.md` for `vault/rust.md`. `None` for an empty path.
+fn source_tag(path: &Path) -> Option<String> {
+    let components: Vec<String> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(heading_component(&name.to_string_lossy())),
+            _ => None,
+        })
+        .collect();
+    (!components.is_empty()).then(|| format!("{}::{}", SOURCE_TAG_PREFIX, components.join("::")))
+}
+
+/// The source tag of the file at `path`, built from its path relative to the sync
+/// `root` so that it does not depend on where the vault lives or on the working
+/// directory. `None` for a file without a name.
+pub fn file_source_tag(path: &Path, root: &Path) -> Option<String> {
+    source_tag(&root_relative_path(path, root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::document::parse_document;
+    use indoc::indoc;
+
+    #[test]
+    fn test_heading_tags() {
+        let input = indoc! {"
+            ---
+            anki_sync:
+              heading_tags: true
+            ---
+            # Rust
+
+            ## Smart Pointers
+
+            ```sh
+            # not a heading
+            ```
+
+            ### Q: What is `Rc`?
+            A reference-counted pointer.
+
+            ## C: A `Box` owns ==heap== memory.
+
+            ## Ownership
+            ### Q: Who drops a value?
+            Its owner.
+        "};
+        let (_, doc) = parse_document(input).unwrap();
+        let tags = heading_tags(&doc);
+        assert_eq!(
+            tags,
+            vec![
+                None,
+                Some("Rust::Smart_Pointers".to_string()),
+                Some("Rust".to_string()),
+                None,
+                Some("Rust::Ownership".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_tag() {
+        assert_eq!(
+            source_tag(Path::new("./vault/My Notes/rust.md")).as_deref(),
+            Some("markdown-anki-sync::vault::My_Notes::rust.md")
+        );
+        assert_eq!(source_tag(Path::new("")), None);
+    }
+
+    #[test]
+    fn test_file_source_tag_relative_to_root() {
+        let tags: Vec<Option<String>> = ["home", "backup"]
+            .into_iter()
+            .map(|place| {
+                let dir = tempfile::tempdir().unwrap();
+                let root = dir.path().join(place).join("vault");
+                std::fs::create_dir_all(root.join("rust")).unwrap();
+                std::fs::write(root.join("rust/notes.md"), "").unwrap();
+                file_source_tag(&root.join("rust/../rust/notes.md"), &root)
+            })
+            .collect();
+        assert_eq!(
+            tags[0].as_deref(),
+            Some("markdown-anki-sync::rust::notes.md")
+        );
+        assert_eq!(tags[0], tags[1]);
+        assert_eq!(
+            file_source_tag(Path::new("other/notes.md"), Path::new("vault")).as_deref(),
+            Some("markdown-anki-sync::notes.md")
+        );
+    }
+
+    #[test]
+    fn test_heading_tags_off_by_default() {
+        let (_, doc) = parse_document("# Rust\n## Q: What is Rust?\nA language.\n").unwrap();
+        assert_eq!(heading_tags(&doc), vec![None, None]);
+    }
+}
@@ -1,3 +1,8 @@
+use crate::anki::DuplicateScope;
+use crate::render::RenderMode;
+use serde::Deserialize;
+
+#[derive(Debug)]
 pub struct MarkdownDocument {
     pub front_matter: Option<FrontMatter>,
     pub blocks: Vec<Block>,
@@ -10,11 +15,60 @@ pub enum FrontMatter {
     },
     AnkiSync {
         raw: String,
-        deck: Option<String>,
-        tags: Vec<String>,
+        settings: SyncSettings,
+    },
+    /// Front matter with `anki_sync` settings that cannot be read.
+    Invalid {
+        raw: String,
+        error: String,
     },
 }
 
+/// The `anki_sync` settings of the front matter, which apply to every card of the file.
+/// A misspelled setting makes the front matter invalid rather than being ignored.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncSettings {
+    pub deck: Option<String>,
+    /// Note type of `## Q:` cards.
+    pub model: Option<String>,
+    pub tags: Vec<String>,
+    /// Tag every card with the path of the headings it is under.
+    pub heading_tags: bool,
+    /// Add the top-level `tags` and `aliases` of the front matter to `tags`.
+    pub front_matter_tags: bool,
+    /// `false` leaves every card of the file alone.
+    pub sync: bool,
+    /// Create notes even when Anki has one with the same first field.
+    pub allow_duplicates: bool,
+    /// Where Anki looks for a note with the same first field.
+    pub duplicate_scope: Option<DuplicateScope>,
+    /// How card text is turned into fields, instead of the command line choice.
+    pub render: Option<RenderMode>,
+    /// Put every tag of the file under this tag, such as `notes::rust` for `rust`.
+    pub tag_prefix: Option<String>,
+    /// Put the cards in a subdeck named after the path of the file, below `deck`.
+    pub deck_from_path: bool,
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        SyncSettings {
+            deck: None,
+            model: None,
+            tags: Vec::new(),
+            heading_tags: false,
+            front_matter_tags: false,
+            sync: true,
+            allow_duplicates: false,
+            duplicate_scope: None,
+            render: None,
+            tag_prefix: None,
+            deck_from_path: false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Block {
     FlashCard(FlashCard),
@@ -23,9 +77,35 @@ pub enum Block {
         blank_line: Option<BlankLine>,
         flashcard: FlashCard,
     },
+    Cloze(ClozeCard),
+    ClozeWithMeta {
+        metadata: FlashCardMetaData,
+        blank_line: Option<BlankLine>,
+        cloze: ClozeCard,
+    },
     Passthrough(PassthroughBlock),
 }
 
+impl Block {
+    pub fn metadata(&self) -> Option<&FlashCardMetaData> {
+        match self {
+            Block::FlashCardWithMeta { metadata, .. } | Block::ClozeWithMeta { metadata, .. } => {
+                Some(metadata)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn metadata_mut(&mut self) -> Option<&mut FlashCardMetaData> {
+        match self {
+            Block::FlashCardWithMeta { metadata, .. } | Block::ClozeWithMeta { metadata, .. } => {
+                Some(metadata)
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FlashCard {
     pub raw: String,
@@ -34,12 +114,42 @@ pub struct FlashCard {
 }
 
 #[derive(Debug, Clone)]
+pub struct ClozeCard {
+    pub raw: String,
+    pub text: String,
+    pub back_extra: String,
+}
+
+/// A named field filled from a `### Name:` sub-section of a card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldSection {
+    pub name: String,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct FlashCardMetaData {
     pub raw: String,
     pub id: Option<u64>,
     pub sync: Option<bool>,
     pub deck: Option<String>,
+    pub model: Option<String>,
+    /// Replaces the tags the card gets from the front matter.
     pub tags: Option<Vec<String>>,
+    /// Added to the tags the card gets from the front matter or `tags`.
+    pub tags_add: Option<Vec<String>>,
+    /// Removed from the tags the card gets from the front matter or `tags`.
+    pub tags_remove: Option<Vec<String>>,
+    /// Create the note even when Anki has one with the same first field.
+    pub allow_duplicate: Option<bool>,
+    /// Where Anki looks for a note with the same first field.
+    pub duplicate_scope: Option<DuplicateScope>,
+    /// Hash of the note as it was last synced from the Markdown.
+    pub hash: Option<String>,
+    /// Modification time of the note in Anki right after it was last synced.
+    pub modified: Option<u64>,
+    /// Keys this tool does not know, with their values as written.
+    pub other: Vec<(String, String)>,
 }
 
 #[derive(Debug, Clone)]
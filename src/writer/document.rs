@@ -4,33 +4,55 @@ impl MarkdownDocument {
     pub fn raw(&self) -> String {
         let mut final_raw = String::new();
         if let Some(ref fm) = self.front_matter {
-            match fm {
-                FrontMatter::Raw { raw } | FrontMatter::AnkiSync { raw, .. } => {
-                    final_raw.push_str(raw);
-                }
-            }
+            final_raw.push_str(fm.raw());
         }
         for block in &self.blocks {
-            match block {
-                Block::FlashCard(card) => {
-                    final_raw.push_str(&card.raw);
-                }
-                Block::FlashCardWithMeta {
-                    metadata,
-                    blank_line,
-                    flashcard,
-                } => {
-                    final_raw.push_str(&metadata.raw);
-                    if let Some(bl) = blank_line {
-                        final_raw.push_str(&bl.raw);
-                    }
-                    final_raw.push_str(&flashcard.raw);
+            final_raw.push_str(&block.raw());
+        }
+        final_raw
+    }
+}
+
+impl FrontMatter {
+    pub fn raw(&self) -> &str {
+        match self {
+            FrontMatter::Raw { raw }
+            | FrontMatter::AnkiSync { raw, .. }
+            | FrontMatter::Invalid { raw, .. } => raw,
+        }
+    }
+}
+
+impl Block {
+    pub fn raw(&self) -> String {
+        match self {
+            Block::FlashCard(card) => card.raw.clone(),
+            Block::FlashCardWithMeta {
+                metadata,
+                blank_line,
+                flashcard,
+            } => {
+                let mut raw = metadata.raw.clone();
+                if let Some(bl) = blank_line {
+                    raw.push_str(&bl.raw);
                 }
-                Block::Passthrough(block) => {
-                    final_raw.push_str(&block.raw);
+                raw.push_str(&flashcard.raw);
+                raw
+            }
+            Block::Cloze(cloze) => cloze.raw.clone(),
+            Block::ClozeWithMeta {
+                metadata,
+                blank_line,
+                cloze,
+            } => {
+                let mut raw = metadata.raw.clone();
+                if let Some(bl) = blank_line {
+                    raw.push_str(&bl.raw);
                 }
+                raw.push_str(&cloze.raw);
+                raw
             }
+            Block::Passthrough(block) => block.raw.clone(),
         }
-        final_raw
     }
 }
@@ -1,54 +1,148 @@
+use crate::anki::DuplicateScope;
+use crate::parser::flashcard_metadata::{
+    MetadataEntry, parse_flashcard_metadata, parse_metadata_entries,
+};
+use crate::parser::tag::format_tag;
 use crate::types::FlashCardMetaData;
 
 impl FlashCardMetaData {
-    pub fn from_fields(
-        id: Option<u64>,
-        deck: Option<String>,
-        sync: Option<bool>,
-        tags: Option<Vec<String>>,
-    ) -> Self {
-        let anki_id = id.map_or_else(|| "".to_string(), |id| format!("anki_id: {}", id));
-        let anki_deck = deck.clone().map_or_else(
-            || "".to_string(),
-            |deck| {
-                if deck.contains(" ") {
-                    format!("anki_deck: \"{}\"", deck)
-                } else {
-                    format!("anki_deck: {}", deck)
-                }
-            },
-        );
-        let anki_sync = sync.map_or_else(|| "".to_string(), |sync| format!("anki_sync: {}", sync));
-        let anki_tags = tags.clone().map_or_else(
-            || "".to_string(),
-            |tags| {
-                let formatted_tags: Vec<String> = tags
-                    .into_iter()
-                    .map(|tag| {
-                        if tag.contains(" ") {
-                            format!("\"{}\"", tag)
-                        } else {
-                            tag
-                        }
-                    })
+    /// The `key: value` pairs of the metadata, in the order a new comment lists them.
+    fn entries(&self) -> Vec<(String, String)> {
+        self.entries_with_id(self.id.map(|id| id.to_string()))
+    }
+
+    /// The pairs of the metadata with `id` written as the `anki_id`.
+    fn entries_with_id(&self, id: Option<String>) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        let mut push = |key: &str, value: String| entries.push((key.to_string(), value));
+        if let Some(id) = id {
+            push("anki_id", id);
+        }
+        if let Some(deck) = &self.deck {
+            push("anki_deck", format_tag(deck));
+        }
+        if let Some(model) = &self.model {
+            push("anki_model", format_tag(model));
+        }
+        if let Some(sync) = self.sync {
+            push("anki_sync", sync.to_string());
+        }
+        let list = |tags: &[String]| {
+            let formatted_tags: Vec<String> = tags.iter().map(|tag| format_tag(tag)).collect();
+            format!("[{}]", formatted_tags.join(", "))
+        };
+        if let Some(tags) = &self.tags {
+            push("anki_tags", list(tags));
+        }
+        if let Some(tags) = &self.tags_add {
+            push("anki_tags_add", list(tags));
+        }
+        if let Some(tags) = &self.tags_remove {
+            push("anki_tags_remove", list(tags));
+        }
+        if let Some(allow) = self.allow_duplicate {
+            push("anki_allow_duplicate", allow.to_string());
+        }
+        if let Some(scope) = self.duplicate_scope {
+            let scope = match scope {
+                DuplicateScope::Deck => "deck",
+                DuplicateScope::Collection => "collection",
+            };
+            push("anki_duplicate_scope", scope.to_string());
+        }
+        if let Some(hash) = &self.hash {
+            push("anki_hash", hash.clone());
+        }
+        if let Some(modified) = self.modified {
+            push("anki_mod", modified.to_string());
+        }
+        entries.extend(self.other.iter().cloned());
+        entries
+    }
+
+    /// The metadata with `raw` rewritten from its fields. A comment that was parsed keeps
+    /// its spacing and key order: changed values are replaced in place, removed keys are
+    /// dropped and new keys are added at the end.
+    pub fn formatted(self) -> Self {
+        let id = self.id.map(|id| id.to_string());
+        self.formatted_with_id(id)
+    }
+
+    /// Like `formatted`, but with `placeholder` written as the `anki_id`, for showing
+    /// the metadata of a note that does not exist yet.
+    pub fn formatted_with_placeholder_id(self, placeholder: &str) -> Self {
+        self.formatted_with_id(Some(placeholder.to_string()))
+    }
+
+    fn formatted_with_id(self, id: Option<String>) -> Self {
+        let entries = self.entries_with_id(id);
+        let raw = match (
+            parse_flashcard_metadata(&self.raw),
+            parse_metadata_entries(&self.raw),
+        ) {
+            (Ok((_, before)), Ok((_, written))) => {
+                rewrite(&self.raw, &written, &before.entries(), &entries)
+            }
+            _ => {
+                let formatted_fields: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
                     .collect();
-                format!("anki_tags: [{}]", formatted_tags.join(", "))
-            },
-        );
-
-        let formatted_fields = vec![anki_id, anki_deck, anki_sync, anki_tags]
-            .into_iter()
-            .filter(|field| !field.is_empty())
-            .collect::<Vec<String>>()
-            .join(", ");
-
-        let raw = format!("<!-- {} -->\n", formatted_fields);
-        FlashCardMetaData {
-            raw,
-            id,
-            deck,
-            sync,
-            tags,
+                format!("<!-- {} -->\n", formatted_fields.join(", "))
+            }
+        };
+        FlashCardMetaData { raw, ..self }
+    }
+}
+
+fn value_of<'a>(entries: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    entries
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Rewrites the `written` pairs of `raw` from the values they had `before` to the
+/// `after` ones, reusing the separator between the first two pairs for new keys.
+fn rewrite(
+    raw: &str,
+    written: &[MetadataEntry],
+    before: &[(String, String)],
+    after: &[(String, String)],
+) -> String {
+    let (Some(first), Some(last)) = (written.first(), written.last()) else {
+        return raw.to_string();
+    };
+    let separator = written
+        .get(1)
+        .map_or(", ", |second| &raw[first.span.end..second.span.start]);
+
+    let mut result = raw[..first.span.start].to_string();
+    let mut kept = 0;
+    for (i, entry) in written.iter().enumerate() {
+        let Some(value) = value_of(after, entry.key) else {
+            continue;
+        };
+        if kept > 0 {
+            result.push_str(&raw[written[i - 1].span.end..entry.span.start]);
+        }
+        if value_of(before, entry.key) == Some(value) {
+            result.push_str(&raw[entry.span.clone()]);
+        } else {
+            result.push_str(&raw[entry.span.start..entry.value.start]);
+            result.push_str(value);
+        }
+        kept += 1;
+    }
+    for (key, value) in after {
+        if written.iter().all(|entry| entry.key != key) {
+            if kept > 0 {
+                result.push_str(separator);
+            }
+            result.push_str(&format!("{}: {}", key, value));
+            kept += 1;
         }
     }
+    result.push_str(&raw[last.span.end..]);
+    result
 }